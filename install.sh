@@ -12,6 +12,7 @@ groupadd -f yarad
 #create directories
 mkdir -p /etc/yarad
 mkdir -p /var/run/yarad
+chown yarad:yarad /var/run/yarad
 mkdir -p /var/lib/yarad/rules
 
 #write CONFIG_TEMPLATE into config.yml
@@ -1,49 +1,32 @@
-use anyhow::anyhow;
 use clap::Parser;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 use std::convert::TryFrom;
+use std::process::exit;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::sleep;
+use yarad::client::{Address, Client};
 use yarad::config;
 use yarad::daemon::{
     command::{Args, Command},
+    pid::{is_running, read_pid, PidFile},
+    worker,
     Yarad,
 };
 use yarad::error::*;
 use std::fs::read_to_string;
 use log::{info, error};
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let args = Args::parse();
     let config_path = args.config_path();
     let config = config::Config::try_from(config_path)?;
-    env_logger::init();
+    yarad::log::init_logger(config.get_log_level())?;
 
     if let Some(command) = args.get_command() {
         match command {
-            Command::Start => {
-                info!("Starting yarad");
-                let mut daemonized = false;
-                loop {
-                    let yarad = Yarad::new(config.clone())?;
-                    if !*args.get_foreground() && !daemonized {
-                        info!("Starting daemon");
-                        yarad.daemonize()?;
-                        daemonized = true;
-                    }
-                    match yarad.run().await {
-                        Ok(_) => {
-                            info!("yarad exited");
-                            break;
-                        }
-                        Err(Error::CompileError(e)) => {
-                            error!("Compile error: {}", e);
-                            break;
-                        }
-                        Err(e) => {
-                            error!("Error: {}", e);
-                        }
-                    }
-                }
-            }
+            Command::Start => start(&args, config)?,
             Command::ShowRulesDir => {
                 println!("{}", config.get_rules_dir());
             }
@@ -51,11 +34,212 @@ async fn main() -> Result<()> {
                 let pid_file = config.get_pid_file();
                 println!("{}", read_to_string(pid_file)?);
             }
-            _ => {
-                return Err(anyhow!("Not implemented").into());
-            }
+            Command::Reload => exit(runtime()?.block_on(reload(&config))),
+            Command::Stop => exit(runtime()?.block_on(stop(&config, *args.get_timeout(), *args.get_kill()))),
+            Command::Status => exit(runtime()?.block_on(status(&config))),
+            Command::Worker => worker::serve(&config)?,
         }
         return Ok(());
     }
     Ok(())
 }
+
+fn runtime() -> Result<Runtime> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+/// Daemonize, listen and drop privileges while single threaded, then start the runtime.
+fn start(args: &Args, config: config::Config) -> Result<()> {
+    info!("Starting yarad");
+    // removes the pid file when yarad exits
+    let mut pid_file = None;
+    if *args.get_foreground() {
+        PidFile::check(config.get_pid_file())?;
+        pid_file = Some(PidFile::create(config.get_pid_file())?);
+    }
+    let mut yarad = Yarad::new(config, args.config_path())?;
+    if pid_file.is_none() {
+        info!("Starting daemon");
+        pid_file = Some(yarad.daemonize()?);
+    }
+    yarad.listen()?;
+    yarad.drop_privileges()?;
+    yarad.sandbox()?;
+    yarad.load_rules()?;
+
+    let runtime = Builder::new_multi_thread().enable_all().build()?;
+    // exits on an error rather than starting over, as the sockets are bound and the privileges
+    // dropped by now. The supervisor restarts yarad
+    let result = runtime.block_on(async move {
+        match yarad.run().await {
+            Ok(_) => {
+                info!("yarad exited");
+                Ok(())
+            }
+            Err(Error::CompileError(e)) => {
+                error!("Compile error: {}", e);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error: {}", e);
+                Err(e)
+            }
+        }
+    });
+    drop(pid_file);
+    result
+}
+
+/// Exit codes of the init script actions (LSB).
+const EXIT_OK: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_NOT_RUNNING: i32 = 7;
+/// Exit codes of `status` (LSB).
+const STATUS_RUNNING: i32 = 0;
+const STATUS_DEAD_PID_FILE: i32 = 1;
+const STATUS_NOT_RUNNING: i32 = 3;
+const STATUS_UNKNOWN: i32 = 4;
+
+/// Pid of the running daemon, `None` when there is none.
+fn running_pid(config: &config::Config) -> Option<i32> {
+    match read_pid(config.get_pid_file()) {
+        Ok(pid) => pid.filter(|pid| is_running(*pid)),
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+/// Send `RELOAD`, falling back to SIGHUP when the socket is unreachable.
+async fn reload(config: &config::Config) -> i32 {
+    match Client::new(Address::from(config)).reload().await {
+        Ok(restart_required) => {
+            println!("RELOADED");
+            if !restart_required.is_empty() {
+                println!("restart required for: {}", restart_required.join(", "));
+            }
+            return EXIT_OK;
+        }
+        Err(Error::DaemonNotRunning) => {}
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return EXIT_FAILURE;
+        }
+    }
+    match running_pid(config) {
+        Some(pid) => match kill(Pid::from_raw(pid), Signal::SIGHUP) {
+            Ok(()) => {
+                println!("sent SIGHUP to {}", pid);
+                EXIT_OK
+            }
+            Err(e) => {
+                eprintln!("ERROR: failed to send SIGHUP to {}: {}", pid, e);
+                EXIT_FAILURE
+            }
+        },
+        None => {
+            eprintln!("yarad is not running");
+            EXIT_NOT_RUNNING
+        }
+    }
+}
+
+/// Send `SHUTDOWN` (or SIGTERM when the socket is unreachable) and wait for the daemon to exit,
+/// SIGKILLing it after `timeout` seconds when `force` is set.
+async fn stop(config: &config::Config, timeout: u64, force: bool) -> i32 {
+    let pid = match running_pid(config) {
+        Some(pid) => pid,
+        None => {
+            println!("yarad is not running");
+            return EXIT_OK;
+        }
+    };
+    if let Err(e) = Client::new(Address::from(config)).shutdown().await {
+        info!("SHUTDOWN failed ({}), sending SIGTERM", e);
+        if let Err(e) = kill(Pid::from_raw(pid), Signal::SIGTERM) {
+            eprintln!("ERROR: failed to send SIGTERM to {}: {}", pid, e);
+            return EXIT_FAILURE;
+        }
+    }
+    if wait_exit(pid, Duration::from_secs(timeout)).await {
+        println!("yarad stopped");
+        return EXIT_OK;
+    }
+    if !force {
+        eprintln!("ERROR: yarad ({}) did not stop within {} seconds", pid, timeout);
+        return EXIT_FAILURE;
+    }
+    eprintln!("yarad ({}) did not stop within {} seconds, killing", pid, timeout);
+    if let Err(e) = kill(Pid::from_raw(pid), Signal::SIGKILL) {
+        eprintln!("ERROR: failed to send SIGKILL to {}: {}", pid, e);
+        return EXIT_FAILURE;
+    }
+    if wait_exit(pid, Duration::from_secs(1)).await {
+        // it could not clean up after itself
+        let _ = std::fs::remove_file(config.get_pid_file());
+        println!("yarad killed");
+        EXIT_OK
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+async fn wait_exit(pid: i32, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while is_running(pid) {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
+
+async fn status(config: &config::Config) -> i32 {
+    let pid = match read_pid(config.get_pid_file()) {
+        Ok(Some(pid)) => pid,
+        Ok(None) => {
+            println!("yarad is not running");
+            return STATUS_NOT_RUNNING;
+        }
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return STATUS_UNKNOWN;
+        }
+    };
+    if !is_running(pid) {
+        println!("yarad is not running, but the pid file {} exists", config.get_pid_file());
+        return STATUS_DEAD_PID_FILE;
+    }
+
+    let client = Client::new(Address::from(config));
+    let stats = match client.ping().await {
+        Ok(()) => client.stats().await,
+        Err(e) => Err(e),
+    };
+    match stats {
+        Ok(stats) => {
+            let field = |name: &str| {
+                stats
+                    .lines()
+                    .find_map(|line| line.strip_prefix(name))
+                    .map(|value| value.trim().to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            };
+            println!("yarad ({}) is running", pid);
+            println!("rules: {}", field("RULES:"));
+            println!("uptime: {}s", field("UPTIME:"));
+            if let Ok(status) = client.status().await {
+                for file in status.skipped {
+                    println!("skipped: {} ({})", file.path, file.error);
+                }
+            }
+            STATUS_RUNNING
+        }
+        Err(e) => {
+            println!("yarad ({}) is running but not responding: {}", pid, e);
+            STATUS_UNKNOWN
+        }
+    }
+}
@@ -1,22 +1,262 @@
 use yarad::{
     error::*,
-    protocol::Command,
-    client::args::Args,
+    client::{
+        args::{Args, Command, QuarantineCommand, RulesCommand, STDIN},
+        blocking::Client,
+        remediate::{remediate, write_manifest},
+        report::{Format, Report, EXIT_CLEAN, EXIT_ERROR},
+    },
 };
-use clap::Parser;
-use std::os::unix::net::UnixStream;
-use std::io::prelude::*;
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use std::fs::{canonicalize, write, File};
+use std::io;
+use std::process::exit;
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+/// Path reported for the data read from stdin.
+const STDIN_RESULT: &str = "stdin";
+
+fn run(args: &Args) -> Result<i32> {
+    let client = Client::new(args.address()?)?.scan_options(args.scan_options()?);
+    let mut report = Report::new()
+        .infected_only(*args.get_infected())
+        .summary_enabled(!*args.get_no_summary() && !*args.get_quiet());
+
+    let command = match args.get_command() {
+        Some(command) => command,
+        None => {
+            let targets = args.targets()?;
+            if targets.is_empty() {
+                Args::command()
+                    .error(ErrorKind::MissingRequiredArgument, "no file, --file-list or command given")
+                    .exit();
+            }
+            for target in &targets {
+                scan_target(&client, target, &mut report);
+            }
+            return finish(args, report);
+        }
+    };
+
+    match command {
+        Command::Ping => {
+            client.ping()?;
+            println!("PONG");
+            return Ok(EXIT_CLEAN);
+        },
+        Command::Version => {
+            println!("{}", client.version()?);
+            return Ok(EXIT_CLEAN);
+        },
+        Command::Reload => {
+            let restart_required = client.reload()?;
+            println!("RELOADED");
+            if !restart_required.is_empty() {
+                println!("restart required for: {}", restart_required.join(", "));
+            }
+            return Ok(EXIT_CLEAN);
+        },
+        Command::Shutdown => {
+            client.shutdown()?;
+            return Ok(EXIT_CLEAN);
+        },
+        Command::Stats => {
+            print!("{}", client.stats()?);
+            return Ok(EXIT_CLEAN);
+        },
+        Command::Status => {
+            let status = client.status()?;
+            if *args.get_format() == Format::Json {
+                println!("{}", serde_json::to_string_pretty(&status.skipped)?);
+            } else {
+                println!("{} rules loaded", status.rules);
+                for file in status.skipped {
+                    println!("{}: skipped, {}", file.path, file.error);
+                }
+            }
+            return Ok(EXIT_CLEAN);
+        },
+        Command::LogLevel{level} => {
+            client.log_level(level)?;
+            println!("LOGLEVEL: {}", level);
+            return Ok(EXIT_CLEAN);
+        },
+        Command::Rules{command} => {
+            let json = *args.get_format() == Format::Json;
+            match command {
+                RulesCommand::List{namespace} => {
+                    let rules = client.rules_list(namespace.as_deref())?;
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&rules)?);
+                    } else {
+                        for rule in rules {
+                            println!("{} [{}] {}", rule.name(), rule.tags.join(","), rule.source.unwrap_or_default());
+                        }
+                    }
+                },
+                RulesCommand::Show{name} => {
+                    let rules = client.rule_show(name)?;
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&rules)?);
+                    } else {
+                        for rule in rules {
+                            println!("{}", rule.name());
+                            println!("  tags: {}", rule.tags.join(", "));
+                            println!("  source: {}", rule.source.unwrap_or_default());
+                            for (key, value) in rule.metadata {
+                                println!("  {}: {}", key, value);
+                            }
+                        }
+                    }
+                },
+                RulesCommand::Disable{name} => {
+                    client.disable_rule(name)?;
+                    println!("{}: disabled", name);
+                },
+                RulesCommand::Enable{name} => {
+                    client.enable_rule(name)?;
+                    println!("{}: enabled", name);
+                },
+                RulesCommand::Disabled => {
+                    for name in client.disabled_rules()? {
+                        println!("{}", name);
+                    }
+                },
+                RulesCommand::Info => {
+                    let info = client.rules_info()?;
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&info)?);
+                    } else {
+                        println!("fingerprint: {}", info.fingerprint);
+                        println!("compiled at: {}", info.compiled_at);
+                        println!("rules: {}", info.rules);
+                        println!("skipped files: {}", info.skipped);
+                    }
+                },
+            }
+            return Ok(EXIT_CLEAN);
+        },
+        Command::Quarantine{command} => {
+            match command {
+                QuarantineCommand::List => {
+                    let entries = client.quarantine_list()?;
+                    if *args.get_format() == Format::Json {
+                        println!("{}", serde_json::to_string_pretty(&entries)?);
+                    } else {
+                        for entry in entries {
+                            println!("{} {} {} {}", entry.id, entry.timestamp, entry.rules.join(","), entry.original_path);
+                        }
+                    }
+                },
+                QuarantineCommand::Restore{id} => println!("{}: restored to '{}'", id, client.quarantine_restore(id)?),
+                QuarantineCommand::Delete{id} => {
+                    client.quarantine_delete(id)?;
+                    println!("{}: deleted", id);
+                },
+            }
+            return Ok(EXIT_CLEAN);
+        },
+        Command::Scan{path} | Command::ContScan{path} | Command::MultiScan{path} => {
+            for p in path {
+                scan_target(&client, p, &mut report);
+            }
+        },
+        Command::InstreamScan{path} => {
+            for p in path {
+                if p == STDIN {
+                    scan_target(&client, p, &mut report);
+                    continue;
+                }
+                match File::open(p).map_err(Error::from).and_then(|f| client.scan_reader(f)) {
+                    Ok(mut result) => {
+                        result.path = p.to_string();
+                        report.add(vec![result]);
+                    },
+                    Err(e) => report.add_error(p, e),
+                }
+            }
+        },
+    }
+
+    finish(args, report)
+}
 
-    let command = Vec::<Command>::from(args.get_command());
+/// Stream stdin for "-", otherwise let the daemon scan the path.
+fn scan_target(client: &Client, target: &str, report: &mut Report) {
+    if target == STDIN {
+        match client.scan_reader(io::stdin().lock()) {
+            Ok(mut result) => {
+                result.path = STDIN_RESULT.to_string();
+                report.add(vec![result]);
+            },
+            Err(e) => report.add_error(target, e),
+        }
+        return;
+    }
+    // the daemon resolves relative paths against its own working directory
+    let path = canonicalize(target)
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| target.to_string());
+    match client.scan_path(&path) {
+        Ok(results) => report.add(results),
+        Err(e) => report.add_error(target, e),
+    }
+}
 
-    let mut stream = UnixStream::connect("/var/run/yarad/yarad.ctl")?;
-    stream.write_all(command.into_iter().map(|c| c.to_string()).collect::<String>().as_bytes())?;
+fn finish(args: &Args, mut report: Report) -> Result<i32> {
+    if let Some(action) = args.action() {
+        // data read from stdin has no file to act on
+        let files = report.results().iter().filter(|r| r.path != STDIN_RESULT);
+        let entries = remediate(&action, files);
+        for entry in &entries {
+            match (&entry.error, &entry.destination) {
+                (Some(e), _) => report.add_error(&entry.source, Error::ActionFailed {
+                    action: entry.action.to_string(),
+                    reason: e.clone(),
+                }),
+                (None, Some(dest)) if !*args.get_quiet() => {
+                    let done = if entry.action == "copy" { "copied" } else { "moved" };
+                    eprintln!("{}: {} to '{}'", entry.source, done, dest);
+                },
+                (None, None) if !*args.get_quiet() => eprintln!("{}: removed", entry.source),
+                _ => {},
+            }
+        }
+        let manifest = args
+            .get_manifest()
+            .as_ref()
+            .map(|path| path.into())
+            .unwrap_or_else(|| action.default_manifest());
+        if !entries.is_empty() {
+            match write_manifest(&manifest, &entries) {
+                Ok(()) if !*args.get_quiet() => eprintln!("manifest written to '{}'", manifest.display()),
+                Ok(()) => {},
+                Err(e) => report.add_error(&manifest.display().to_string(), e),
+            }
+        }
+    }
 
-    let mut resp = String::new();
-    stream.read_to_string(&mut resp)?;
-    print!("{}", resp);
-    Ok(())
+    report.finish();
+    let output = report.render(*args.get_format())?;
+    if *args.get_quiet() {
+        for error in report.errors() {
+            eprintln!("{}: {} ERROR", error.target, error.error);
+        }
+    } else {
+        print!("{}", output);
+    }
+    if let Some(path) = args.get_report() {
+        write(path, output)?;
+    }
+    Ok(report.exit_code())
+}
+
+fn main() {
+    let args = Args::parse();
+    match run(&args) {
+        Ok(code) => exit(code),
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            exit(EXIT_ERROR);
+        }
+    }
 }
@@ -1 +1,309 @@
 pub mod args;
+pub mod blocking;
+pub mod remediate;
+pub mod report;
+
+use crate::config::{Config, StreamType};
+use crate::daemon::quarantine::QuarantineEntry;
+use crate::daemon::rule::{RuleInfo, RulesetInfo};
+use crate::error::*;
+use crate::protocol::{self, Command, RulesStatus, ScanOptions, INSTREAM_CHUNK_SIZE, INSTREAM_PATH, SESSION_DONE};
+use crate::scan::ScanResult;
+use crate::sock::Stream;
+use std::io;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpStream, UnixStream};
+
+/// Where the daemon listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Unix(PathBuf),
+    Tcp(String, u16),
+}
+
+impl From<&Config> for Address {
+    /// The address the daemon with `config` listens on.
+    fn from(config: &Config) -> Self {
+        match config.get_stream_type() {
+            StreamType::Unix => Address::Unix(config.get_local_socket().into()),
+            StreamType::Tcp => Address::Tcp("127.0.0.1".to_string(), *config.get_tcp_port()),
+        }
+    }
+}
+
+impl Address {
+    async fn connect(&self) -> Result<Stream> {
+        let stream = match self {
+            Address::Unix(path) => UnixStream::connect(path).await.map(Stream::Unix),
+            Address::Tcp(host, port) => TcpStream::connect((&host[..], *port)).await.map(Stream::Tcp),
+        };
+        stream.map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => Error::DaemonNotRunning,
+            _ => e.into(),
+        })
+    }
+}
+
+/// Async client of the yarad daemon. Every request is sent over a new connection; use
+/// [`Client::session`] to send several requests over one.
+#[derive(Debug, Clone)]
+pub struct Client {
+    address: Address,
+    scan_options: ScanOptions,
+}
+
+impl Client {
+    pub fn new(address: Address) -> Self {
+        Client {
+            address,
+            scan_options: ScanOptions::default(),
+        }
+    }
+
+    /// Options sent with every scan, e.g. the namespaces to report.
+    pub fn scan_options(mut self, options: ScanOptions) -> Self {
+        self.scan_options = options;
+        self
+    }
+
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Self {
+        Client::new(Address::Unix(path.into()))
+    }
+
+    pub fn tcp<H: Into<String>>(host: H, port: u16) -> Self {
+        Client::new(Address::Tcp(host.into(), port))
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    async fn request(&self, command: Command) -> Result<String> {
+        let mut stream = self.address.connect().await?;
+        stream.write_all(command.to_string().as_bytes()).await?;
+        read_reply(stream).await
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        expect(&self.request(Command::Ping).await?, "PONG")
+    }
+
+    pub async fn version(&self) -> Result<String> {
+        Ok(protocol::check_reply(&self.request(Command::Version).await?)?.trim_end().to_string())
+    }
+
+    /// Returns the changed settings that only take effect after a restart of the daemon.
+    pub async fn reload(&self) -> Result<Vec<String>> {
+        protocol::parse_reload_reply(&self.request(Command::Reload).await?)
+    }
+
+    pub async fn stats(&self) -> Result<String> {
+        Ok(protocol::check_reply(&self.request(Command::Stats).await?)?.to_string())
+    }
+
+    /// The number of loaded rules and the rule files skipped because they failed to compile.
+    pub async fn status(&self) -> Result<RulesStatus> {
+        protocol::parse_status_reply(&self.request(Command::Status).await?)
+    }
+
+    /// The loaded rules, of `namespace` only when given.
+    pub async fn rules_list(&self, namespace: Option<&str>) -> Result<Vec<RuleInfo>> {
+        protocol::parse_rules_list(&self.request(Command::RulesList(namespace.map(String::from))).await?)
+    }
+
+    /// The rules named `name`, optionally qualified as `namespace:name`.
+    pub async fn rule_show(&self, name: &str) -> Result<Vec<RuleInfo>> {
+        protocol::parse_rules_list(&self.request(Command::RulesShow(name.to_string())).await?)
+    }
+
+    /// Fingerprint and compile time of the loaded rules.
+    pub async fn rules_info(&self) -> Result<RulesetInfo> {
+        protocol::parse_rules_info(&self.request(Command::RulesInfo).await?)
+    }
+
+    /// Stop reporting the matches of `name`, given as `namespace:name`.
+    pub async fn disable_rule(&self, name: &str) -> Result<()> {
+        expect(&self.request(Command::RuleDisable(name.to_string())).await?, &format!("DISABLED: {}", name))
+    }
+
+    pub async fn enable_rule(&self, name: &str) -> Result<()> {
+        expect(&self.request(Command::RuleEnable(name.to_string())).await?, &format!("ENABLED: {}", name))
+    }
+
+    pub async fn disabled_rules(&self) -> Result<Vec<String>> {
+        let reply = self.request(Command::RuleListDisabled).await?;
+        Ok(protocol::check_reply(&reply)?.lines().map(String::from).collect())
+    }
+
+    /// Change the daemon's log level until its next reload.
+    pub async fn log_level(&self, level: &str) -> Result<()> {
+        let reply = self.request(Command::LogLevel(level.to_string())).await?;
+        expect(&reply, &format!("LOGLEVEL: {}", level.to_uppercase()))
+    }
+
+    pub async fn shutdown(&self) -> Result<()> {
+        expect(&self.request(Command::Shutdown).await?, "SHUTDOWN")
+    }
+
+    pub async fn quarantine_list(&self) -> Result<Vec<QuarantineEntry>> {
+        protocol::parse_quarantine_list(&self.request(Command::QuarantineList).await?)
+    }
+
+    /// Restore a quarantined file, returning the path it was restored to.
+    pub async fn quarantine_restore(&self, id: &str) -> Result<String> {
+        let reply = self.request(Command::QuarantineRestore(id.to_string())).await?;
+        protocol::check_reply(&reply)?
+            .trim_end()
+            .strip_prefix("RESTORED: ")
+            .map(String::from)
+            .ok_or_else(|| Error::UnexpectedReply(reply.clone()))
+    }
+
+    pub async fn quarantine_delete(&self, id: &str) -> Result<()> {
+        expect(&self.request(Command::QuarantineDelete(id.to_string())).await?, &format!("DELETED: {}", id))
+    }
+
+    /// Scan a file or directory on the daemon's host.
+    pub async fn scan_path(&self, path: &str) -> Result<Vec<ScanResult>> {
+        let command = Command::Scan(path.to_string(), self.scan_options.clone());
+        protocol::parse_scan_reply(&self.request(command).await?)
+    }
+
+    /// Send `data` to the daemon and scan it.
+    pub async fn scan_bytes(&self, data: &[u8]) -> Result<ScanResult> {
+        self.scan_reader(data).await
+    }
+
+    /// Stream everything read from `reader` to the daemon and scan it.
+    pub async fn scan_reader<R: AsyncRead + Unpin>(&self, reader: R) -> Result<ScanResult> {
+        let mut stream = self.address.connect().await?;
+        send_instream(&mut stream, reader, &self.scan_options).await?;
+        single_result(&read_reply(stream).await?)
+    }
+
+    /// Open a session: the following requests share one connection.
+    pub async fn session(&self) -> Result<Session> {
+        let mut stream = self.address.connect().await?;
+        stream.write_all(Command::IdSession.to_string().as_bytes()).await?;
+        Ok(Session {
+            stream,
+            buf: Vec::new(),
+            id: 0,
+            scan_options: self.scan_options.clone(),
+        })
+    }
+}
+
+/// Requests sent over one connection, started by `IDSESSION`.
+#[derive(Debug)]
+pub struct Session {
+    stream: Stream,
+    buf: Vec<u8>,
+    id: u64,
+    scan_options: ScanOptions,
+}
+
+impl Session {
+    async fn request(&mut self, command: Command) -> Result<String> {
+        self.stream.write_all(command.to_string().as_bytes()).await?;
+        self.read_reply().await
+    }
+
+    /// Read the lines prefixed with the current request's number up to its `DONE` line.
+    async fn read_reply(&mut self) -> Result<String> {
+        self.id += 1;
+        let prefix = format!("{}: ", self.id);
+        let mut reply = String::new();
+        loop {
+            while let Some(end) = self.buf.iter().position(|c| *c == b'\n') {
+                let line = String::from_utf8(self.buf.drain(..=end).collect())?;
+                let line = line.trim_end_matches('\n');
+                let line = match line.strip_prefix(&prefix) {
+                    Some(line) => line,
+                    None => {
+                        // the daemon closes the session with an unprefixed reply when rejecting it
+                        protocol::check_reply(line)?;
+                        return Err(Error::UnexpectedReply(line.to_string()));
+                    }
+                };
+                if line == SESSION_DONE {
+                    return Ok(reply);
+                }
+                reply.push_str(line);
+                reply.push('\n');
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(Error::UnexpectedReply("connection closed".to_string()));
+            }
+        }
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        expect(&self.request(Command::Ping).await?, "PONG")
+    }
+
+    pub async fn version(&mut self) -> Result<String> {
+        Ok(protocol::check_reply(&self.request(Command::Version).await?)?.trim_end().to_string())
+    }
+
+    pub async fn reload(&mut self) -> Result<Vec<String>> {
+        protocol::parse_reload_reply(&self.request(Command::Reload).await?)
+    }
+
+    pub async fn stats(&mut self) -> Result<String> {
+        Ok(protocol::check_reply(&self.request(Command::Stats).await?)?.to_string())
+    }
+
+    pub async fn scan_path(&mut self, path: &str) -> Result<Vec<ScanResult>> {
+        let command = Command::Scan(path.to_string(), self.scan_options.clone());
+        protocol::parse_scan_reply(&self.request(command).await?)
+    }
+
+    pub async fn scan_bytes(&mut self, data: &[u8]) -> Result<ScanResult> {
+        self.scan_reader(data).await
+    }
+
+    pub async fn scan_reader<R: AsyncRead + Unpin>(&mut self, reader: R) -> Result<ScanResult> {
+        send_instream(&mut self.stream, reader, &self.scan_options).await?;
+        single_result(&self.read_reply().await?)
+    }
+
+    /// End the session and close the connection.
+    pub async fn end(mut self) -> Result<()> {
+        self.stream.write_all(Command::End.to_string().as_bytes()).await
+    }
+}
+
+async fn send_instream<R: AsyncRead + Unpin>(stream: &mut Stream, mut reader: R, options: &ScanOptions) -> Result<()> {
+    stream.write_all(Command::Instream(options.clone()).to_string().as_bytes()).await?;
+    let mut chunk = vec![0; INSTREAM_CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        stream.write_all(&protocol::instream_chunk(&chunk[..n])).await?;
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+async fn read_reply(mut stream: Stream) -> Result<String> {
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    Ok(String::from_utf8(reply)?)
+}
+
+fn expect(reply: &str, expected: &str) -> Result<()> {
+    if protocol::check_reply(reply)?.trim_end() == expected {
+        Ok(())
+    } else {
+        Err(Error::UnexpectedReply(reply.to_string()))
+    }
+}
+
+fn single_result(reply: &str) -> Result<ScanResult> {
+    protocol::parse_scan_reply(reply)?
+        .into_iter()
+        .find(|result| result.path == INSTREAM_PATH)
+        .ok_or_else(|| Error::UnexpectedReply(reply.to_string()))
+}
@@ -1,5 +1,14 @@
 use clap::{Parser, Subcommand};
+use log::warn;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read};
 use tia::Tia;
+use crate::client::{remediate::Action, report::Format, Address};
+use crate::config::{Config, DEFAULT_CONFIG_PATH, DEFAULT_SOCKET_PATH};
+use crate::error::*;
+use crate::protocol::ScanOptions;
 
 #[derive(Debug, Parser, Tia)]
 #[clap(author, version, about, long_about=None)]
@@ -8,11 +17,62 @@ pub struct Args {
     /// Save scan report in FILE
     #[clap(short, long)]
     report: Option<String>,
+    /// Output and report format
+    #[clap(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Only print infected files
+    #[clap(short, long)]
+    infected: bool,
+    /// Only output error messages
+    #[clap(long)]
+    quiet: bool,
+    /// Disable the summary at the end of scanning
+    #[clap(long)]
+    no_summary: bool,
+    /// Move infected files into DIR, numbering the name of a file already there
+    #[clap(long = "move", value_name = "DIR", conflicts_with_all = ["copy", "remove"])]
+    move_to: Option<String>,
+    /// Copy infected files into DIR, numbering the name of a file already there
+    #[clap(long, value_name = "DIR", conflicts_with = "remove")]
+    copy: Option<String>,
+    /// Remove infected files
+    #[clap(long)]
+    remove: bool,
+    /// Write the list of moved, copied or removed files to FILE, by default
+    /// yaradscan-manifest-<time>.json in DIR or, with --remove, in the current directory
+    #[clap(long, value_name = "FILE")]
+    manifest: Option<String>,
+    /// daemon config file to read the socket settings from
+    #[clap(short, long)]
+    config: Option<String>,
+    /// connect to the unix socket at PATH
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["host", "port"])]
+    socket: Option<String>,
+    /// connect to the daemon over TCP at HOST
+    #[clap(long)]
+    host: Option<String>,
+    /// connect to the daemon over TCP at PORT
+    #[clap(long)]
+    port: Option<u16>,
+    /// Report only the matches of rules in these namespaces
+    #[clap(long, value_name = "NAMESPACE", value_delimiter = ',')]
+    namespace: Vec<String>,
+    /// Set the rules' external variable NAME for each scanned file, overriding the daemon's
+    #[clap(long = "var", value_name = "NAME=VALUE")]
+    vars: Vec<String>,
+    /// Scan the paths listed in FILE, separated by newlines or NULs ("-" for stdin)
+    #[clap(long, value_name = "FILE")]
+    file_list: Option<String>,
+    /// Files or directories to scan, "-" to scan stdin
+    path: Vec<String>,
     /// Command
     #[clap(subcommand)]
-    command: Command,
+    command: Option<Command>,
 }
 
+/// Target name standing for stdin.
+pub const STDIN: &str = "-";
+
 #[derive(Debug, Subcommand)]
 pub enum Command {
     /// ping to daemon
@@ -23,6 +83,15 @@ pub enum Command {
     Reload,
     /// shutdown daemon
     Shutdown,
+    /// daemon statistics
+    Stats,
+    /// loaded rules and the rule files skipped because they failed to compile
+    Status,
+    /// change the daemon's log level until its next reload
+    LogLevel {
+        /// off, error, warn, info, debug or trace
+        level: String,
+    },
     /// scan
     Scan {
         #[arg(required = true)]
@@ -44,4 +113,150 @@ pub enum Command {
         #[arg(required = true)]
         path: Vec<String>
     },
+    /// inspect the daemon's rules
+    Rules {
+        #[clap(subcommand)]
+        command: RulesCommand,
+    },
+    /// manage the daemon's quarantine
+    Quarantine {
+        #[clap(subcommand)]
+        command: QuarantineCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RulesCommand {
+    /// list the loaded rules
+    List {
+        /// only the rules of this namespace
+        namespace: Option<String>,
+    },
+    /// show the rules with this name, optionally qualified as namespace:name
+    Show {
+        name: String,
+    },
+    /// show the fingerprint and compile time of the loaded rules
+    Info,
+    /// stop reporting the matches of a rule until it is enabled again
+    Disable {
+        /// namespace:name
+        name: String,
+    },
+    /// report the matches of a disabled rule again
+    Enable {
+        /// namespace:name
+        name: String,
+    },
+    /// list the disabled rules
+    Disabled,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum QuarantineCommand {
+    /// list quarantined files
+    List,
+    /// restore a quarantined file to its original path
+    Restore {
+        id: String,
+    },
+    /// delete a quarantined file
+    Delete {
+        id: String,
+    },
+}
+
+impl Args {
+    /// Targets given on the command line followed by the ones in `--file-list`.
+    pub fn targets(&self) -> Result<Vec<String>> {
+        let mut targets = self.path.clone();
+        if let Some(ref list) = self.file_list {
+            if list == STDIN && targets.iter().any(|t| t == STDIN) {
+                return Err(Error::InvalidPath("stdin can not be both the file list and a target".to_string()));
+            }
+            let mut content = String::new();
+            if list == STDIN {
+                io::stdin().read_to_string(&mut content)?;
+            } else {
+                File::open(list)?.read_to_string(&mut content)?;
+            }
+            let separator = if content.contains('\0') { '\0' } else { '\n' };
+            targets.extend(
+                content
+                    .split(separator)
+                    .map(|line| line.trim_end_matches('\r'))
+                    .filter(|line| !line.is_empty())
+                    .map(String::from),
+            );
+        }
+        Ok(targets)
+    }
+
+    /// Options sent with each scan.
+    pub fn scan_options(&self) -> Result<ScanOptions> {
+        let mut externals = BTreeMap::new();
+        for var in &self.vars {
+            match var.split_once('=') {
+                Some((name, value)) if !name.is_empty() && !value.contains(char::is_whitespace) => {
+                    externals.insert(name.to_string(), value.to_string());
+                }
+                _ => return Err(Error::InvalidCommand(format!("--var {}: expected NAME=VALUE without spaces", var))),
+            }
+        }
+        Ok(ScanOptions {
+            namespaces: self.namespace.clone(),
+            externals,
+        })
+    }
+
+    /// The remediation requested by `--move`, `--copy` or `--remove`.
+    pub fn action(&self) -> Option<Action> {
+        if let Some(ref dir) = self.move_to {
+            Some(Action::Move(dir.into()))
+        } else if let Some(ref dir) = self.copy {
+            Some(Action::Copy(dir.into()))
+        } else if self.remove {
+            Some(Action::Remove)
+        } else {
+            None
+        }
+    }
+
+    /// Resolve the daemon address: `--socket` and `--host`/`--port` take precedence over the
+    /// settings of the daemon config file.
+    pub fn address(&self) -> Result<Address> {
+        if let Some(ref socket) = self.socket {
+            return Ok(Address::Unix(socket.into()));
+        }
+        let config = self.load_config()?;
+        if self.host.is_some() || self.port.is_some() {
+            let host = self.host.clone().unwrap_or_else(|| "127.0.0.1".to_string());
+            let port = match (self.port, &config) {
+                (Some(port), _) => port,
+                (None, Some(config)) => *config.get_tcp_port(),
+                (None, None) => return Err(Error::ConfigLack("tcp_port")),
+            };
+            return Ok(Address::Tcp(host, port));
+        }
+        match config {
+            Some(config) => Ok(Address::from(&config)),
+            None => Ok(Address::Unix(DEFAULT_SOCKET_PATH.into())),
+        }
+    }
+
+    /// An explicitly given config must be loadable; the default one is optional, since it is
+    /// usually not readable by unprivileged users.
+    fn load_config(&self) -> Result<Option<Config>> {
+        match self.config {
+            Some(ref path) => Config::try_from(path.clone()).map(Some),
+            None => match Config::try_from(DEFAULT_CONFIG_PATH.to_string()) {
+                Ok(config) => Ok(Some(config)),
+                Err(e @ Error::ConfigNotFound(_)) | Err(e @ Error::ConfigPermissionDenied(_)) => {
+                    warn!("{}, using the default socket", e);
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            },
+        }
+    }
 }
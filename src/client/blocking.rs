@@ -0,0 +1,183 @@
+//! Blocking wrappers of [`crate::client::Client`] and [`crate::client::Session`] for callers
+//! without a tokio runtime.
+
+use crate::daemon::quarantine::QuarantineEntry;
+use crate::daemon::rule::{RuleInfo, RulesetInfo};
+use crate::error::*;
+use crate::protocol::{RulesStatus, ScanOptions};
+use crate::scan::ScanResult;
+use super::Address;
+use std::io::Read;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::runtime::{Builder, Runtime};
+
+#[derive(Debug)]
+pub struct Client {
+    inner: super::Client,
+    runtime: Runtime,
+}
+
+impl Client {
+    pub fn new(address: Address) -> Result<Self> {
+        Ok(Client {
+            inner: super::Client::new(address),
+            runtime: Builder::new_current_thread().enable_all().build()?,
+        })
+    }
+
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        Client::new(Address::Unix(path.into()))
+    }
+
+    pub fn tcp<H: Into<String>>(host: H, port: u16) -> Result<Self> {
+        Client::new(Address::Tcp(host.into(), port))
+    }
+
+    pub fn address(&self) -> &Address {
+        self.inner.address()
+    }
+
+    /// Options sent with every scan, e.g. the namespaces to report.
+    pub fn scan_options(mut self, options: ScanOptions) -> Self {
+        self.inner = self.inner.scan_options(options);
+        self
+    }
+
+    pub fn ping(&self) -> Result<()> {
+        self.runtime.block_on(self.inner.ping())
+    }
+
+    pub fn version(&self) -> Result<String> {
+        self.runtime.block_on(self.inner.version())
+    }
+
+    pub fn reload(&self) -> Result<Vec<String>> {
+        self.runtime.block_on(self.inner.reload())
+    }
+
+    pub fn stats(&self) -> Result<String> {
+        self.runtime.block_on(self.inner.stats())
+    }
+
+    pub fn status(&self) -> Result<RulesStatus> {
+        self.runtime.block_on(self.inner.status())
+    }
+
+    pub fn rules_list(&self, namespace: Option<&str>) -> Result<Vec<RuleInfo>> {
+        self.runtime.block_on(self.inner.rules_list(namespace))
+    }
+
+    pub fn rule_show(&self, name: &str) -> Result<Vec<RuleInfo>> {
+        self.runtime.block_on(self.inner.rule_show(name))
+    }
+
+    pub fn rules_info(&self) -> Result<RulesetInfo> {
+        self.runtime.block_on(self.inner.rules_info())
+    }
+
+    pub fn disable_rule(&self, name: &str) -> Result<()> {
+        self.runtime.block_on(self.inner.disable_rule(name))
+    }
+
+    pub fn enable_rule(&self, name: &str) -> Result<()> {
+        self.runtime.block_on(self.inner.enable_rule(name))
+    }
+
+    pub fn disabled_rules(&self) -> Result<Vec<String>> {
+        self.runtime.block_on(self.inner.disabled_rules())
+    }
+
+    pub fn log_level(&self, level: &str) -> Result<()> {
+        self.runtime.block_on(self.inner.log_level(level))
+    }
+
+    pub fn shutdown(&self) -> Result<()> {
+        self.runtime.block_on(self.inner.shutdown())
+    }
+
+    pub fn quarantine_list(&self) -> Result<Vec<QuarantineEntry>> {
+        self.runtime.block_on(self.inner.quarantine_list())
+    }
+
+    pub fn quarantine_restore(&self, id: &str) -> Result<String> {
+        self.runtime.block_on(self.inner.quarantine_restore(id))
+    }
+
+    pub fn quarantine_delete(&self, id: &str) -> Result<()> {
+        self.runtime.block_on(self.inner.quarantine_delete(id))
+    }
+
+    pub fn scan_path(&self, path: &str) -> Result<Vec<ScanResult>> {
+        self.runtime.block_on(self.inner.scan_path(path))
+    }
+
+    pub fn scan_bytes(&self, data: &[u8]) -> Result<ScanResult> {
+        self.runtime.block_on(self.inner.scan_bytes(data))
+    }
+
+    pub fn scan_reader<R: Read + Unpin>(&self, reader: R) -> Result<ScanResult> {
+        self.runtime.block_on(self.inner.scan_reader(SyncReader(reader)))
+    }
+
+    pub fn session(&self) -> Result<Session<'_>> {
+        Ok(Session {
+            inner: self.runtime.block_on(self.inner.session())?,
+            runtime: &self.runtime,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Session<'a> {
+    inner: super::Session,
+    runtime: &'a Runtime,
+}
+
+impl Session<'_> {
+    pub fn ping(&mut self) -> Result<()> {
+        self.runtime.block_on(self.inner.ping())
+    }
+
+    pub fn version(&mut self) -> Result<String> {
+        self.runtime.block_on(self.inner.version())
+    }
+
+    pub fn reload(&mut self) -> Result<Vec<String>> {
+        self.runtime.block_on(self.inner.reload())
+    }
+
+    pub fn stats(&mut self) -> Result<String> {
+        self.runtime.block_on(self.inner.stats())
+    }
+
+    pub fn scan_path(&mut self, path: &str) -> Result<Vec<ScanResult>> {
+        self.runtime.block_on(self.inner.scan_path(path))
+    }
+
+    pub fn scan_bytes(&mut self, data: &[u8]) -> Result<ScanResult> {
+        self.runtime.block_on(self.inner.scan_bytes(data))
+    }
+
+    pub fn scan_reader<R: Read + Unpin>(&mut self, reader: R) -> Result<ScanResult> {
+        self.runtime.block_on(self.inner.scan_reader(SyncReader(reader)))
+    }
+
+    pub fn end(self) -> Result<()> {
+        self.runtime.block_on(self.inner.end())
+    }
+}
+
+/// Adapts a blocking reader for the client running on the current thread runtime, where
+/// blocking inside `poll_read` only blocks the caller.
+struct SyncReader<R>(R);
+
+impl<R: Read + Unpin> AsyncRead for SyncReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let n = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
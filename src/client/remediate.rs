@@ -0,0 +1,156 @@
+use nix::errno::Errno;
+use nix::fcntl::{renameat2, RenameFlags};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::*;
+use crate::scan::ScanResult;
+
+/// What to do with the infected files after scanning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Move(PathBuf),
+    Copy(PathBuf),
+    Remove,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Move(_) => "move",
+            Action::Copy(_) => "copy",
+            Action::Remove => "remove",
+        }
+    }
+
+    /// Where the manifest goes when not given explicitly, named after the time so that the one
+    /// of an earlier run is kept.
+    pub fn default_manifest(&self) -> PathBuf {
+        let name = format!("yaradscan-manifest-{}.json", now());
+        match self {
+            Action::Move(dir) | Action::Copy(dir) => dir.join(name),
+            Action::Remove => PathBuf::from(name),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    pub action: &'static str,
+    pub source: String,
+    pub destination: Option<String>,
+    pub rules: Vec<String>,
+    pub timestamp: u64,
+    pub error: Option<String>,
+}
+
+/// Apply `action` to every infected file in `results`.
+pub fn remediate<'a, I: IntoIterator<Item = &'a ScanResult>>(action: &Action, results: I) -> Vec<ManifestEntry> {
+    results
+        .into_iter()
+        .filter(|result| !result.is_clean())
+        .map(|result| {
+            let source = Path::new(&result.path);
+            let intended = match action {
+                Action::Move(dir) | Action::Copy(dir) => Some(destination(dir, source)),
+                Action::Remove => None,
+            };
+            let done = match (action, &intended) {
+                (Action::Move(_), Some(dest)) => move_file(source, dest).map(Some),
+                (Action::Copy(_), Some(dest)) => copy_file(source, dest).map(Some),
+                _ => fs::remove_file(source).map(|_| None).map_err(Error::from),
+            };
+            let (destination, error) = match done {
+                Ok(placed) => (placed, None),
+                Err(e) => (intended, Some(e.to_string())),
+            };
+            ManifestEntry {
+                action: action.name(),
+                source: result.path.clone(),
+                destination: destination.map(|d| d.display().to_string()),
+                rules: result.matches().cloned().collect(),
+                timestamp: now(),
+                error,
+            }
+        })
+        .collect()
+}
+
+pub fn write_manifest<P: AsRef<Path>>(path: P, entries: &[ManifestEntry]) -> Result<()> {
+    let file = File::create(path)?;
+    serde_json::to_writer_pretty(file, entries)?;
+    Ok(())
+}
+
+/// Keep the source path below `dir`, so that files with the same name do not collide.
+fn destination(dir: &Path, source: &Path) -> PathBuf {
+    let relative = source
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect::<PathBuf>();
+    dir.join(relative)
+}
+
+/// Copy to `dest`, or to the first of `dest.1`, `dest.2`, .. that doesn't exist. Returns where
+/// it went.
+fn copy_file(source: &Path, dest: &Path) -> Result<PathBuf> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    first_free(dest, |candidate| copy_new(source, candidate))
+}
+
+/// Move to `dest`, or to the first of `dest.1`, `dest.2`, .. that doesn't exist. Returns where
+/// it went.
+fn move_file(source: &Path, dest: &Path) -> Result<PathBuf> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    first_free(dest, |candidate| {
+        match renameat2(None, source, None, candidate, RenameFlags::RENAME_NOREPLACE) {
+            Ok(()) => Ok(()),
+            // the destination is on another filesystem
+            Err(Errno::EXDEV) => {
+                copy_new(source, candidate)?;
+                fs::remove_file(source).map_err(Error::from)
+            }
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    })
+}
+
+/// Copy to `dest`, failing when it exists.
+fn copy_new(source: &Path, dest: &Path) -> Result<()> {
+    let mut from = File::open(source)?;
+    let mut to = OpenOptions::new().write(true).create_new(true).open(dest)?;
+    io::copy(&mut from, &mut to)?;
+    to.set_permissions(from.metadata()?.permissions())?;
+    Ok(())
+}
+
+/// Run `put` with `dest`, then with numbered variants of it while they exist.
+fn first_free<F: FnMut(&Path) -> Result<()>>(dest: &Path, mut put: F) -> Result<PathBuf> {
+    for n in 0.. {
+        let candidate = if n == 0 {
+            dest.to_path_buf()
+        } else {
+            let mut name = dest.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".{}", n));
+            dest.with_file_name(name)
+        };
+        match put(&candidate) {
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            done => return done.map(|_| candidate),
+        }
+    }
+    unreachable!()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
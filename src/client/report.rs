@@ -0,0 +1,269 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use crate::error::*;
+use crate::scan::ScanResult;
+
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// one line per result followed by the scan summary
+    Text,
+    /// results, errors and summary as one JSON document
+    Json,
+    /// one row per result; no summary
+    Csv,
+    /// SARIF 2.1.0 log, the summary is kept in the run properties
+    Sarif,
+}
+
+/// A target that could not be scanned.
+#[derive(Debug, Serialize)]
+pub struct ScanError {
+    pub target: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub files_scanned: usize,
+    pub infected_files: usize,
+    pub errors: usize,
+    pub elapsed_secs: f64,
+}
+
+/// Exit code when nothing was found.
+pub const EXIT_CLEAN: i32 = 0;
+/// Exit code when at least one file matched.
+pub const EXIT_INFECTED: i32 = 1;
+/// Exit code when nothing matched but some error occurred.
+pub const EXIT_ERROR: i32 = 2;
+
+#[derive(Debug)]
+pub struct Report {
+    results: Vec<ScanResult>,
+    errors: Vec<ScanError>,
+    started: Instant,
+    elapsed: Option<Duration>,
+    infected_only: bool,
+    summary: bool,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Report::new()
+    }
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report {
+            results: Vec::new(),
+            errors: Vec::new(),
+            started: Instant::now(),
+            elapsed: None,
+            infected_only: false,
+            summary: true,
+        }
+    }
+
+    /// Leave clean files out of the rendered report.
+    pub fn infected_only(mut self, infected_only: bool) -> Self {
+        self.infected_only = infected_only;
+        self
+    }
+
+    /// Render the scan summary.
+    pub fn summary_enabled(mut self, summary: bool) -> Self {
+        self.summary = summary;
+        self
+    }
+
+    /// Files the daemon failed to scan are added as errors.
+    pub fn add(&mut self, results: Vec<ScanResult>) {
+        for result in results {
+            match result.error {
+                Some(error) => self.errors.push(ScanError {
+                    target: result.path,
+                    error,
+                }),
+                None => self.results.push(result),
+            }
+        }
+    }
+
+    pub fn add_error(&mut self, target: &str, error: Error) {
+        self.errors.push(ScanError {
+            target: target.to_string(),
+            error: error.to_string(),
+        });
+    }
+
+    /// Stop the clock.
+    pub fn finish(&mut self) {
+        self.elapsed = Some(self.started.elapsed());
+    }
+
+    pub fn results(&self) -> &[ScanResult] {
+        &self.results
+    }
+
+    pub fn errors(&self) -> &[ScanError] {
+        &self.errors
+    }
+
+    /// clamdscan compatible exit code: matches take precedence over errors.
+    pub fn exit_code(&self) -> i32 {
+        if self.results.iter().any(|r| !r.is_clean()) {
+            EXIT_INFECTED
+        } else if !self.errors.is_empty() {
+            EXIT_ERROR
+        } else {
+            EXIT_CLEAN
+        }
+    }
+
+    fn rendered_results(&self) -> impl Iterator<Item = &ScanResult> {
+        let infected_only = self.infected_only;
+        self.results.iter().filter(move |r| !infected_only || !r.is_clean())
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            files_scanned: self.results.len(),
+            infected_files: self.results.iter().filter(|r| !r.is_clean()).count(),
+            errors: self.errors.len(),
+            elapsed_secs: self.elapsed.unwrap_or_else(|| self.started.elapsed()).as_secs_f64(),
+        }
+    }
+
+    pub fn render(&self, format: Format) -> Result<String> {
+        Ok(match format {
+            Format::Text => self.render_text(),
+            Format::Json => {
+                let mut report = json!({
+                    "results": self.rendered_results().collect::<Vec<_>>(),
+                    "errors": self.errors,
+                });
+                if self.summary {
+                    report["summary"] = serde_json::to_value(self.summary())?;
+                }
+                serde_json::to_string_pretty(&report)? + "\n"
+            },
+            Format::Csv => self.render_csv(),
+            Format::Sarif => serde_json::to_string_pretty(&self.sarif())? + "\n",
+        })
+    }
+
+    fn render_text(&self) -> String {
+        let mut out = String::new();
+        for result in self.rendered_results() {
+            for rule in &result.rule {
+                out.push_str(&format!("{}: {}\n", rule, result.path));
+            }
+            for action in &result.actions {
+                out.push_str(&format!("{}: {}\n", action, result.path));
+            }
+        }
+        for error in &self.errors {
+            out.push_str(&format!("{}: {} ERROR\n", error.target, error.error));
+        }
+        if self.summary {
+            out.push_str(&self.summary().to_string());
+        }
+        out
+    }
+
+    fn render_csv(&self) -> String {
+        let mut out = String::from("path,status,rules,error\n");
+        for result in self.rendered_results() {
+            let status = if result.is_clean() { "clean" } else { "infected" };
+            let rules = result.matches().cloned().collect::<Vec<_>>().join(";");
+            out.push_str(&format!("{},{},{},\n", csv_field(&result.path), status, csv_field(&rules)));
+        }
+        for error in &self.errors {
+            out.push_str(&format!("{},error,,{}\n", csv_field(&error.target), csv_field(&error.error)));
+        }
+        out
+    }
+
+    fn sarif(&self) -> serde_json::Value {
+        let rules = self
+            .results
+            .iter()
+            .flat_map(|r| r.matches())
+            .collect::<BTreeSet<_>>();
+        let results = self
+            .results
+            .iter()
+            .flat_map(|result| {
+                result.matches().map(move |rule| {
+                    json!({
+                        "ruleId": rule,
+                        "level": "error",
+                        "message": { "text": format!("{} matched {}", result.path, rule) },
+                        "locations": [{
+                            "physicalLocation": { "artifactLocation": { "uri": result.path } }
+                        }],
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let notifications = self
+            .errors
+            .iter()
+            .map(|error| {
+                json!({
+                    "level": "error",
+                    "message": { "text": format!("{}: {}", error.target, error.error) },
+                })
+            })
+            .collect::<Vec<_>>();
+        let properties = if self.summary {
+            json!({ "summary": self.summary() })
+        } else {
+            json!({})
+        };
+
+        json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": "yaradscan",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "informationUri": "https://github.com/n01e0/yarad",
+                        "rules": rules.into_iter().map(|rule| json!({ "id": rule })).collect::<Vec<_>>(),
+                    }
+                },
+                "invocations": [{
+                    "executionSuccessful": self.errors.is_empty(),
+                    "toolExecutionNotifications": notifications,
+                }],
+                "results": results,
+                "properties": properties,
+            }]
+        })
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed_secs as u64;
+        writeln!(f, "\n----------- SCAN SUMMARY -----------")?;
+        writeln!(f, "Scanned files: {}", self.files_scanned)?;
+        writeln!(f, "Infected files: {}", self.infected_files)?;
+        writeln!(f, "Total errors: {}", self.errors)?;
+        writeln!(f, "Time: {:.3} sec ({} m {} s)", self.elapsed_secs, secs / 60, secs % 60)
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
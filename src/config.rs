@@ -2,11 +2,13 @@ use crate::error::*;
 use clap::Parser;
 use tia::Tia;
 use log::Level;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::read_to_string;
 use std::str::FromStr;
 
 pub const DEFAULT_CONFIG_PATH: &str = "/etc/yarad/config.yml";
+pub const DEFAULT_SOCKET_PATH: &str = "/var/run/yarad/yarad.ctl";
 
 
 #[derive(Debug, Parser, Tia)]
@@ -32,9 +34,23 @@ struct ConfigFile {
     rules_dir: Option<String>,
     working_dir: Option<String>,
     user: Option<String>,
+    group: Option<String>,
     auto_recompile_rules: Option<bool>,
+    skip_broken_rules: Option<bool>,
+    externals: Option<Externals>,
+    admin_uids: Option<Vec<u32>>,
     pid_file: Option<String>,
+    stdout_log: Option<String>,
+    stderr_log: Option<String>,
     scan_timeout: Option<i32>,
+    stream_max_length: Option<u64>,
+    limits: Option<LimitsFile>,
+    quarantine: Option<QuarantineFile>,
+    policy: Option<Vec<PolicyFile>>,
+    event_log: Option<EventLogFile>,
+    webhook: Option<WebhookFile>,
+    sandbox: Option<SandboxFile>,
+    workers: Option<WorkersFile>,
 }
 
 #[derive(Debug, Tia, Eq, PartialEq, Clone)]
@@ -49,9 +65,240 @@ pub struct Config {
     rules_dir: String,
     working_dir: String,
     user: String,
+    group: String,
     auto_recompile_rules: bool,
+    /// leave out rule files failing to compile instead of failing to start
+    skip_broken_rules: bool,
+    /// external variables the rules are compiled with, and their defaults
+    externals: Externals,
+    /// allowed to run the admin commands besides root and the daemon's user
+    admin_uids: Vec<u32>,
     pid_file: String,
+    /// stdout of the daemonized process
+    stdout_log: String,
+    /// stderr of the daemonized process, where the log goes
+    stderr_log: String,
     scan_timeout: i32,
+    stream_max_length: u64,
+    limits: Limits,
+    quarantine: QuarantineConfig,
+    policy: Vec<Policy>,
+    event_log: EventLogConfig,
+    webhook: WebhookConfig,
+    sandbox: SandboxConfig,
+    workers: WorkersConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct LimitsFile {
+    max_concurrent_scans: Option<usize>,
+    max_requests_per_second: Option<u32>,
+    max_stream_bytes_per_minute: Option<u64>,
+    max_files_per_scan: Option<usize>,
+}
+
+/// Per peer resource quotas. `None` means unlimited.
+#[derive(Debug, Tia, Eq, PartialEq, Clone, Default)]
+#[tia(rg)]
+pub struct Limits {
+    max_concurrent_scans: Option<usize>,
+    max_requests_per_second: Option<u32>,
+    max_stream_bytes_per_minute: Option<u64>,
+    max_files_per_scan: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QuarantineFile {
+    on_match: Option<bool>,
+    dir: Option<String>,
+}
+
+#[derive(Debug, Tia, Eq, PartialEq, Clone)]
+#[tia(rg)]
+pub struct QuarantineConfig {
+    /// move matched files into the quarantine
+    on_match: bool,
+    dir: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventLogFile {
+    file: Option<String>,
+    max_size: Option<u64>,
+    keep: Option<usize>,
+    syslog: Option<bool>,
+    journald: Option<bool>,
+}
+
+/// Where detection events are written.
+#[derive(Debug, Tia, Eq, PartialEq, Clone)]
+#[tia(rg)]
+pub struct EventLogConfig {
+    /// JSON lines file, disabled when `None`
+    file: Option<String>,
+    /// size in bytes at which the file is rotated
+    max_size: u64,
+    /// number of rotated files to keep
+    keep: usize,
+    syslog: bool,
+    journald: bool,
+}
+
+impl Default for EventLogConfig {
+    fn default() -> Self {
+        EventLogConfig {
+            file: None,
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+            syslog: false,
+            journald: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookFile {
+    urls: Option<Vec<String>>,
+    batch_interval: Option<u64>,
+    retries: Option<u32>,
+    backoff: Option<u64>,
+    timeout: Option<u64>,
+    spool_dir: Option<String>,
+}
+
+/// HTTP endpoints the match reports are posted to.
+#[derive(Debug, Tia, Eq, PartialEq, Clone)]
+#[tia(rg)]
+pub struct WebhookConfig {
+    /// disabled when empty
+    urls: Vec<String>,
+    /// seconds to collect reports into one post, 0 to post each match
+    batch_interval: u64,
+    retries: u32,
+    /// seconds before the first retry, doubled on each retry
+    backoff: u64,
+    /// request timeout in seconds
+    timeout: u64,
+    /// undelivered reports, retried until the endpoint is back
+    spool_dir: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SandboxFile {
+    seccomp: Option<bool>,
+    landlock: Option<bool>,
+    scan_roots: Option<Vec<String>>,
+}
+
+#[derive(Debug, Tia, Eq, PartialEq, Clone)]
+#[tia(rg)]
+pub struct SandboxConfig {
+    /// syscall allowlist
+    seccomp: bool,
+    /// file system access limited to the paths yarad needs
+    landlock: bool,
+    /// directories readable for scanning with landlock
+    scan_roots: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkersFile {
+    count: Option<usize>,
+    memory_limit: Option<u64>,
+    timeout: Option<u64>,
+    load_timeout: Option<u64>,
+}
+
+#[derive(Debug, Tia, Eq, PartialEq, Clone)]
+#[tia(rg)]
+pub struct WorkersConfig {
+    /// scan worker processes, 0 to scan in the daemon
+    count: usize,
+    /// address space limit of a worker in MiB
+    memory_limit: Option<u64>,
+    /// seconds before a worker is killed, longer than `scan_timeout`
+    timeout: u64,
+    /// seconds a new worker may take to load the rules before it is killed
+    load_timeout: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct PolicyFile {
+    name: Option<String>,
+    rule: Option<Vec<String>>,
+    tag: Option<Vec<String>>,
+    meta: Option<BTreeMap<String, serde_yaml::Value>>,
+    actions: Vec<PolicyAction>,
+    command: Option<String>,
+}
+
+/// Actions to take when a rule selected by name, tag or metadata matches. Empty selectors
+/// select every rule.
+#[derive(Debug, Tia, Eq, PartialEq, Clone)]
+#[tia(rg)]
+pub struct Policy {
+    name: String,
+    rules: Vec<String>,
+    tags: Vec<String>,
+    metadata: BTreeMap<String, String>,
+    actions: Vec<PolicyAction>,
+    /// command run by `exec`, with the JSON match report on stdin
+    command: Option<String>,
+}
+
+/// Value of an external variable of the rules.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum External {
+    Bool(bool),
+    Integer(i64),
+    String(String),
+}
+
+impl External {
+    fn type_name(&self) -> &'static str {
+        match self {
+            External::Bool(_) => "boolean",
+            External::Integer(_) => "integer",
+            External::String(_) => "string",
+        }
+    }
+}
+
+/// External variables by name.
+pub type Externals = BTreeMap<String, External>;
+
+/// The external variables set for each scanned file, with an empty value of their type.
+pub fn builtin_externals() -> Externals {
+    let mut externals = Externals::new();
+    for name in ["filename", "filepath", "extension", "owner"].iter() {
+        externals.insert(name.to_string(), External::String(String::new()));
+    }
+    externals.insert("filesize".to_string(), External::Integer(0));
+    externals
+}
+
+/// Declared in the order the actions run on a file.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Deserialize, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Log,
+    Exec,
+    Chmod,
+    Quarantine,
+    Delete,
+}
+
+impl PolicyAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyAction::Log => "log",
+            PolicyAction::Exec => "exec",
+            PolicyAction::Chmod => "chmod",
+            PolicyAction::Quarantine => "quarantine",
+            PolicyAction::Delete => "delete",
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize, Clone)]
@@ -60,6 +307,83 @@ pub enum StreamType {
     Tcp,
 }
 
+impl Config {
+    /// Settings changed in `new` that only take effect after a restart.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.stream_type != new.stream_type {
+            changed.push("stream_type");
+        }
+        if self.local_socket != new.local_socket {
+            changed.push("local_socket");
+        }
+        if self.local_socket_group != new.local_socket_group {
+            changed.push("local_socket_group");
+        }
+        if self.local_socket_mode != new.local_socket_mode {
+            changed.push("local_socket_mode");
+        }
+        if self.tcp_port != new.tcp_port {
+            changed.push("tcp_port");
+        }
+        // the sandbox only allows reading the one it started with
+        if self.sandbox.landlock && self.rules_dir != new.rules_dir {
+            changed.push("rules_dir");
+        }
+        if self.working_dir != new.working_dir {
+            changed.push("working_dir");
+        }
+        if self.user != new.user {
+            changed.push("user");
+        }
+        if self.group != new.group {
+            changed.push("group");
+        }
+        if self.pid_file != new.pid_file {
+            changed.push("pid_file");
+        }
+        if self.stdout_log != new.stdout_log {
+            changed.push("stdout_log");
+        }
+        if self.stderr_log != new.stderr_log {
+            changed.push("stderr_log");
+        }
+        if self.quarantine.dir != new.quarantine.dir {
+            changed.push("quarantine.dir");
+        }
+        if self.event_log != new.event_log {
+            changed.push("event_log");
+        }
+        if self.webhook != new.webhook {
+            changed.push("webhook");
+        }
+        if self.sandbox != new.sandbox {
+            changed.push("sandbox");
+        }
+        if self.workers != new.workers {
+            changed.push("workers");
+        }
+        // the capabilities they need are dropped at start otherwise
+        if self.modifies_files() != new.modifies_files() {
+            changed.push("policy");
+        }
+        changed
+    }
+
+    /// Whether a policy runs `action`.
+    pub fn uses(&self, action: PolicyAction) -> bool {
+        self.policy.iter().any(|p| p.actions.contains(&action))
+    }
+
+    /// Whether matched files are changed, by quarantine, delete or chmod.
+    pub fn modifies_files(&self) -> bool {
+        self.quarantine.on_match
+            || [PolicyAction::Quarantine, PolicyAction::Delete, PolicyAction::Chmod]
+                .iter()
+                .any(|action| self.uses(*action))
+    }
+}
+
 impl std::convert::TryFrom<String> for Config {
     type Error = Error;
     fn try_from(path: String) -> Result<Self> {
@@ -82,7 +406,7 @@ impl ConfigFile {
             .into();
         let local_socket = self 
             .local_socket
-            .unwrap_or("/var/run/yarad/yarad.ctl".into());
+            .unwrap_or(DEFAULT_SOCKET_PATH.into());
         let local_socket_group = self.local_socket_group.unwrap_or("yarad".into());
         let local_socket_mode: u32 = {
             let mut perm = self.local_socket_mode.unwrap_or("0o666".into());
@@ -94,11 +418,90 @@ impl ConfigFile {
         let rules_dir = self.rules_dir.unwrap_or("/var/lib/yarad/rules".into());
         let working_dir = self.working_dir.unwrap_or("/var/run/yarad".into());
         let user = self.user.unwrap_or("yarad".into());
+        let group = self.group.unwrap_or("yarad".into());
         let auto_recompile_rules = self.auto_recompile_rules.unwrap_or(true);
+        let skip_broken_rules = self.skip_broken_rules.unwrap_or(false);
+        let externals = self.externals.unwrap_or_default();
+        let builtins = builtin_externals();
+        // would be redeclared with another type when compiling
+        for (name, value) in &externals {
+            if let Some(builtin) = builtins.get(name).filter(|b| b.type_name() != value.type_name()) {
+                return Err(Error::ConfigParseError {
+                    reason: format!("external `{}` is set for each file as a {}, got a {}", name, builtin.type_name(), value.type_name()),
+                });
+            }
+        }
+        let admin_uids = self.admin_uids.unwrap_or_default();
         let pid_file = self.pid_file.unwrap_or("/var/run/yarad/yarad.pid".into());
+        let stdout_log = self.stdout_log.unwrap_or("/var/log/yarad.out".into());
+        let stderr_log = self.stderr_log.unwrap_or("/var/log/yarad.log".into());
         let stream_type = self.stream_type.unwrap_or(StreamType::Unix);
         let tcp_port = self.tcp_port.unwrap_or(0);
         let scan_timeout = self.scan_timeout.unwrap_or(5);
+        let stream_max_length = self.stream_max_length.unwrap_or(25 * 1024 * 1024);
+        let limits = self.limits.map(LimitsFile::convert).unwrap_or_default();
+        let quarantine_dir = format!("{}/quarantine", working_dir);
+        let quarantine = match self.quarantine {
+            Some(q) => QuarantineConfig {
+                on_match: q.on_match.unwrap_or(false),
+                dir: q.dir.unwrap_or(quarantine_dir),
+            },
+            None => QuarantineConfig {
+                on_match: false,
+                dir: quarantine_dir,
+            },
+        };
+
+        let sandbox = match self.sandbox {
+            Some(s) => SandboxConfig {
+                seccomp: s.seccomp.unwrap_or(false),
+                landlock: s.landlock.unwrap_or(false),
+                scan_roots: s.scan_roots.unwrap_or_else(|| vec!["/".into()]),
+            },
+            None => SandboxConfig {
+                seccomp: false,
+                landlock: false,
+                scan_roots: vec!["/".into()],
+            },
+        };
+
+        let worker_timeout = scan_timeout.max(0) as u64 + 5;
+        let workers = match self.workers {
+            Some(w) => WorkersConfig {
+                count: w.count.unwrap_or(0),
+                memory_limit: w.memory_limit,
+                timeout: w.timeout.unwrap_or(worker_timeout),
+                load_timeout: w.load_timeout.unwrap_or(300),
+            },
+            None => WorkersConfig {
+                count: 0,
+                memory_limit: None,
+                timeout: worker_timeout,
+                load_timeout: 300,
+            },
+        };
+
+        let policy = self
+            .policy
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| p.convert(i))
+            .collect::<Result<Vec<_>>>()?;
+
+        let event_log = self.event_log.map(EventLogFile::convert).unwrap_or_default();
+        let spool_dir = format!("{}/spool", working_dir);
+        let webhook = match self.webhook {
+            Some(w) => w.convert(spool_dir),
+            None => WebhookConfig {
+                urls: Vec::new(),
+                batch_interval: 0,
+                retries: 3,
+                backoff: 1,
+                timeout: 10,
+                spool_dir,
+            },
+        };
 
         Ok(Config {
             log_level,
@@ -110,9 +513,93 @@ impl ConfigFile {
             rules_dir,
             working_dir,
             user,
+            group,
             auto_recompile_rules,
+            skip_broken_rules,
+            externals,
+            admin_uids,
             pid_file,
-            scan_timeout
+            stdout_log,
+            stderr_log,
+            scan_timeout,
+            stream_max_length,
+            limits,
+            quarantine,
+            policy,
+            event_log,
+            webhook,
+            sandbox,
+            workers,
         })
     }
 }
+
+impl PolicyFile {
+    fn convert(self, index: usize) -> Result<Policy> {
+        if self.actions.contains(&PolicyAction::Exec) && self.command.is_none() {
+            return Err(Error::ConfigLack("policy command"));
+        }
+        let metadata = self
+            .meta
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_yaml::Value::String(s) => s,
+                    serde_yaml::Value::Number(n) => n.to_string(),
+                    serde_yaml::Value::Bool(b) => b.to_string(),
+                    other => return Err(Error::ConfigParseError {
+                        reason: format!("policy meta `{}` must be a scalar, got {:?}", key, other),
+                    }),
+                };
+                Ok((key, value))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+
+        Ok(Policy {
+            name: self.name.unwrap_or_else(|| format!("policy #{}", index)),
+            rules: self.rule.unwrap_or_default(),
+            tags: self.tag.unwrap_or_default(),
+            metadata,
+            actions: self.actions,
+            command: self.command,
+        })
+    }
+}
+
+impl EventLogFile {
+    fn convert(self) -> EventLogConfig {
+        let default = EventLogConfig::default();
+        EventLogConfig {
+            file: self.file,
+            max_size: self.max_size.unwrap_or(default.max_size),
+            keep: self.keep.unwrap_or(default.keep),
+            syslog: self.syslog.unwrap_or(default.syslog),
+            journald: self.journald.unwrap_or(default.journald),
+        }
+    }
+}
+
+impl WebhookFile {
+    fn convert(self, spool_dir: String) -> WebhookConfig {
+        WebhookConfig {
+            urls: self.urls.unwrap_or_default(),
+            batch_interval: self.batch_interval.unwrap_or(0),
+            retries: self.retries.unwrap_or(3),
+            backoff: self.backoff.unwrap_or(1),
+            timeout: self.timeout.unwrap_or(10),
+            spool_dir: self.spool_dir.unwrap_or(spool_dir),
+        }
+    }
+}
+
+impl LimitsFile {
+    fn convert(self) -> Limits {
+        Limits {
+            max_concurrent_scans: self.max_concurrent_scans.filter(|n| *n > 0),
+            max_requests_per_second: self.max_requests_per_second.filter(|n| *n > 0),
+            max_stream_bytes_per_minute: self.max_stream_bytes_per_minute.filter(|n| *n > 0),
+            max_files_per_scan: self.max_files_per_scan.filter(|n| *n > 0),
+        }
+    }
+}
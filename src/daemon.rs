@@ -1,194 +1,718 @@
+pub mod action;
 pub mod command;
+pub mod limit;
+pub mod pid;
+pub mod privilege;
+pub mod quarantine;
 pub mod rule;
+pub mod sandbox;
+pub mod stats;
+pub mod systemd;
+pub mod webhook;
+pub mod worker;
 
-use daemonize::{Daemonize, User};
+use daemonize::Daemonize;
+use nix::unistd::getuid;
 use log::{info, warn, error};
-use nix::unistd::geteuid;
-use std::fs::{create_dir, OpenOptions};
+use std::fs::OpenOptions;
+use std::io;
 use std::path::Path;
+use std::time::{Duration, Instant};
 use tia::Tia;
-use yara::{Rules, Compiler};
 use walkdir::WalkDir;
-use crate::config::Config;
+use crate::config::{Config, External, Externals, StreamType};
 use crate::error::*;
-use crate::sock::Listener;
-use crate::scan::ScanResult;
-use crate::protocol::Command;
-use tokio::sync::Mutex;
-use std::sync::Arc;
+use crate::log::EventLog;
+use crate::sock::{Listener, StdListener, Stream};
+use crate::scan::{RuleMatch, ScanResult};
+use crate::protocol::{error_line, skipped_line, take_command, Command, ScanOptions, ACTION_PREFIX, INSTREAM_PATH, RESTART_REQUIRED_PREFIX, SESSION_DONE};
+use limit::{Peer, RateLimiter};
+use pid::PidFile;
+use rule::{DisabledRules, RuleSet};
+use quarantine::Quarantine;
+use stats::Stats;
+use webhook::Webhook;
+use worker::WorkerPool;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, Notify};
+use std::convert::TryFrom;
+use std::sync::{Arc, RwLock};
 
 #[derive(Tia)]
 #[tia(rg)]
 pub struct Yarad {
-    config: Config,
-    rules: Arc<Mutex<Rules>>,
+    /// replaced on reload, so take a snapshot with `config()`
+    config: RwLock<Arc<Config>>,
+    /// re-read on reload
+    config_path: String,
+    rules: Arc<Mutex<RuleSet>>,
+    limiter: RateLimiter,
+    stats: Stats,
+    quarantine: Quarantine,
+    events: EventLog,
+    webhook: Option<Webhook>,
+    /// rules whose matches are not reported
+    disabled: DisabledRules,
+    /// scans in separate processes when enabled
+    workers: Option<WorkerPool>,
+    /// notified by `SHUTDOWN` to stop the main loop
+    shutdown: Notify,
+    /// held while reloading
+    reloading: Mutex<()>,
+    /// opened by `listen`, and whether it was passed by systemd
+    listener: std::sync::Mutex<Option<(StdListener, bool)>>,
 }
 
 impl Yarad {
-    pub fn new(config: Config) -> Result<Self> {
-        let rules_dir = config.get_rules_dir().to_string();
+    pub fn new(config: Config, config_path: String) -> Result<Self> {
+        // written after dropping privileges, e.g. the pid file is removed there
+        privilege::create_dir_for_user(config.get_working_dir(), config.get_user())?;
+        if let Some(file) = config.get_event_log().get_file() {
+            // rotation renames and creates the files in the directory
+            if let Some(dir) = Path::new(file).parent() {
+                privilege::dedicated_dir(dir, config.get_user())?;
+            }
+        }
+        let limiter = RateLimiter::new(config.get_limits().clone());
+        let quarantine = Quarantine::new(config.get_quarantine().get_dir(), config.get_user())?;
+        let events = EventLog::new(config.get_event_log().clone())?;
+        let webhook = Webhook::new(config.get_webhook().clone(), config.get_user())?;
+        let workers = WorkerPool::new(config.get_workers().clone(), config_path.clone());
+        let disabled = DisabledRules::new(config.get_working_dir());
         Ok(Self {
-            config,
-            rules: Arc::new(Mutex::new(compile_rules(&rules_dir)?)),
+            config: RwLock::new(Arc::new(config)),
+            config_path,
+            rules: Arc::new(Mutex::new(RuleSet::empty())),
+            limiter,
+            stats: Stats::default(),
+            quarantine,
+            events,
+            webhook,
+            disabled,
+            workers,
+            shutdown: Notify::new(),
+            reloading: Mutex::new(()),
+            listener: std::sync::Mutex::new(None),
         })
     }
 
-    async fn scan(&self, path: String) -> Result<Vec<ScanResult>> {
-        let mut results = Vec::new();
-        let rules = self.rules.lock().await;
-        
+    async fn scan(&self, path: String, options: &ScanOptions) -> Result<Vec<ScanResult>> {
+        let max_files = self.limiter.max_files_per_scan();
         let target = Path::new(&path);
-        if target.is_dir() {
-            for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+        let files = if target.is_dir() {
+            let mut files = Vec::new();
+            for entry in WalkDir::new(&path).into_iter().filter_map(|e| e.ok()) {
                 if entry.file_type().is_file() {
-                    let matches = rules.scan_file(entry.path(), *self.config.get_scan_timeout())?;
-                    results.push(ScanResult::new(matches, format!("{}", entry.path().display())));
+                    if max_files.map_or(false, |max| files.len() >= max) {
+                        return Err(Error::LimitExceeded("max files per scan"));
+                    }
+                    files.push(format!("{}", entry.path().display()));
                 }
             }
+            files
         } else if target.is_file() {
-            let matches = rules.scan_file(&path, *self.config.get_scan_timeout())?;
-            if matches.is_empty() {
-                results.push(ScanResult{rule: vec!["OK".to_string()], path});
-            } else {
-                results.push(ScanResult::new(matches, path));
-            }
+            vec![path]
         } else {
             return Err(Error::InvalidPath(path));
+        };
+
+        let overrides = self.rules.lock().await.parse_externals(&options.externals)?;
+        let mut results = Vec::new();
+        if let Some(workers) = &self.workers {
+            for file in files {
+                let externals = file_externals(Path::new(&file), &overrides);
+                match workers.scan_file(&file, externals).await {
+                    Ok((matches, id)) => results.push(ScanResult::from_matches(self.select(matches, options), file).scanned(id)),
+                    Err(e) => results.push(ScanResult::failed(file, e.to_string())),
+                }
+            }
+        } else {
+            let timeout = *self.config().get_scan_timeout();
+            let rules = self.rules.lock().await;
+            for file in files {
+                let (matches, id) = rules.scan_file(&file, timeout, &file_externals(Path::new(&file), &overrides))?;
+                results.push(ScanResult::from_matches(self.select(matches, options), file).scanned(id));
+            }
         }
 
+        let matches = results.iter().flat_map(|r| r.matches()).count();
+        self.stats.scanned(results.len() as u64, matches as u64);
         Ok(results)
     }
 
+    async fn scan_bytes(&self, data: &[u8], options: &ScanOptions) -> Result<ScanResult> {
+        let mut externals = self.rules.lock().await.parse_externals(&options.externals)?;
+        // the size is known, the other file variables only when given by the request
+        externals
+            .entry("filesize".to_string())
+            .or_insert(External::Integer(data.len() as i64));
+        let result = match &self.workers {
+            Some(workers) => match workers.scan_bytes(data, externals).await {
+                Ok(matches) => ScanResult::from_matches(self.select(matches, options), INSTREAM_PATH.to_string()),
+                Err(e) => ScanResult::failed(INSTREAM_PATH.to_string(), e.to_string()),
+            },
+            None => {
+                let timeout = *self.config().get_scan_timeout();
+                let rules = self.rules.lock().await;
+                let matches = rules.scan_mem(data, timeout, &externals)?;
+                ScanResult::from_matches(self.select(matches, options), INSTREAM_PATH.to_string())
+            }
+        };
+        self.stats.scanned(1, result.matches().count() as u64);
+        Ok(result)
+    }
+
+    /// Open the listener, or adopt the one passed by systemd, before privileges are dropped.
+    /// `run` binds it itself otherwise.
+    pub fn listen(&self) -> Result<()> {
+        // a socket passed by systemd is owned by it
+        let listener = match systemd::take_listen_fd()? {
+            Some(fd) => (StdListener::from_fd(fd)?, true),
+            None => (StdListener::bind(&self.config())?, false),
+        };
+        *self.listener.lock().unwrap_or_else(|e| e.into_inner()) = Some(listener);
+        Ok(())
+    }
+
+    /// Switch to the configured user and group. Capabilities are per thread, so this has to
+    /// happen before the runtime starts its threads.
+    pub fn drop_privileges(&self) -> Result<()> {
+        let config = self.config();
+        let workers = *config.get_workers().get_count() > 0;
+        let retained = privilege::retained(config.modifies_files());
+        privilege::drop_privileges(config.get_user(), config.get_group(), &retained, workers)
+    }
+
+    /// Compile or load the rules and read the disabled ones. Only after `drop_privileges`:
+    /// `working_dir` belongs to the daemon's user, and root must not load a cache planted there.
+    pub fn load_rules(&mut self) -> Result<()> {
+        self.rules = Arc::new(Mutex::new(RuleSet::load(&self.config())?));
+        self.disabled.read()
+    }
+
+    /// Apply the sandbox enabled in the config. Like `drop_privileges`, before the runtime starts.
+    pub fn sandbox(&self) -> Result<()> {
+        sandbox::apply(&self.config(), &self.config_path)
+    }
+
+    /// One line summary for the service manager.
+    async fn status(&self) -> String {
+        let rules = self.rules.lock().await;
+        match rules.skipped().len() {
+            0 => format!("{} rules loaded", rules.rules().len()),
+            skipped => format!("{} rules loaded, {} files skipped", rules.rules().len(), skipped),
+        }
+    }
+
+    /// Snapshot of the current config.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
     pub async fn run(self) -> Result<()> {
-        let config = Arc::new(Mutex::new(self.config.clone()));
         info!("yarad started");
 
-        let listener = Listener::new(&*config.lock().await).await?;
+        let opened = self.listener.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let (listener, activated) = match opened {
+            Some((listener, activated)) => (Listener::from_std(listener)?, activated),
+            None => (Listener::new(&self.config()).await?, false),
+        };
+        if let Some(webhook) = &self.webhook {
+            webhook.start();
+        }
+        let watchdog = systemd::start_watchdog();
+        systemd::notify_or_warn(&format!("READY=1\nSTATUS={}", self.status().await));
+        let yarad = Arc::new(self);
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
 
         info!("starting main loop");
-        let main_loop: Result<()> = tokio::spawn(async move {
-            loop {
-                let stream = listener.accept().await?;
-                stream.readable().await?;
-                let commands = stream.try_parse_commands()?;
-                info!("received {} commands", commands.len());
-                for command in commands {
-                    if let Err(Error::InvalidCommand(e)) = command {
-                        let message = format!("Invalid command: {}", e);
-                        warn!("Received {}", message);
-                        stream.try_write(message.as_bytes())?;
-                        continue;
+        loop {
+            tokio::select! {
+                stream = listener.accept() => {
+                    let stream = stream?;
+                    let yarad = yarad.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = yarad.handle(stream).await {
+                            error!("Error while handling connection: {}", e);
+                        }
+                    });
+                }
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP");
+                    // connections keep being accepted while the rules compile
+                    let yarad = yarad.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = yarad.reload().await {
+                            error!("Error while reloading: {}", e);
+                        }
+                    });
+                }
+                _ = terminate.recv() => {
+                    info!("Received SIGTERM");
+                    break;
+                }
+                _ = interrupt.recv() => {
+                    info!("Received SIGINT");
+                    break;
+                }
+                _ = yarad.shutdown.notified() => break,
+            }
+        }
+
+        info!("shutting down");
+        systemd::notify_or_warn("STOPPING=1");
+        if let Some(watchdog) = watchdog {
+            watchdog.abort();
+        }
+        let config = yarad.config();
+        if !activated && *config.get_stream_type() == StreamType::Unix {
+            if let Err(e) = std::fs::remove_file(config.get_local_socket()) {
+                warn!("failed to remove {}: {}", config.get_local_socket(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-read the config file, recompile the rules and apply the settings that can change
+    /// while running. Returns the changed settings that need a restart.
+    async fn reload(&self) -> Result<Vec<&'static str>> {
+        // one at a time, whether by SIGHUP or RELOAD
+        let _reloading = self.reloading.lock().await;
+        systemd::notify_or_warn("RELOADING=1\nSTATUS=reloading");
+        let reloaded = self.reload_config().await;
+        let status = match reloaded {
+            Ok(_) => self.status().await,
+            Err(ref e) => format!("reload failed: {}", e),
+        };
+        systemd::notify_or_warn(&format!("READY=1\nSTATUS={}", status));
+        reloaded
+    }
+
+    async fn reload_config(&self) -> Result<Vec<&'static str>> {
+        info!("reloading {}", self.config_path);
+        let new = Config::try_from(self.config_path.clone())?;
+        let old = self.config();
+        let restart_required = old.restart_required(&new);
+
+        // the sandbox keeps the new rules_dir out of reach until a restart
+        let compiled = if restart_required.contains(&"rules_dir") { (*old).clone() } else { new.clone() };
+        info!("recompiling rules in {}", compiled.get_rules_dir());
+        let new_rules = tokio::task::spawn_blocking(move || RuleSet::load(&compiled)).await??;
+        *self.rules.lock().await = new_rules;
+        info!("recompilation done");
+        if let Some(workers) = &self.workers {
+            workers.restart().await;
+        }
+
+        if let Err(e) = crate::log::apply_log_level(new.get_log_level()) {
+            error!("Failed to apply the log level: {}", e);
+        }
+        self.limiter.set_limits(new.get_limits().clone());
+        if !restart_required.is_empty() {
+            warn!("changes to {} take effect after a restart", restart_required.join(", "));
+        }
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(new);
+        Ok(restart_required)
+    }
+
+    async fn handle(&self, mut stream: Stream) -> Result<()> {
+        let peer = stream.peer();
+        let mut buf = Vec::new();
+        let mut session: Option<u64> = None;
+        let mut handled = 0;
+        loop {
+            let command = match take_command(&mut buf) {
+                Ok(Some(command)) => command,
+                Ok(None) => {
+                    // Outside of a session, a connection serves the commands sent at once.
+                    if session.is_none() && handled > 0 && buf.is_empty() {
+                        break;
+                    }
+                    match self.fill(&mut stream, &mut buf, &peer).await {
+                        Ok(0) => break,
+                        Ok(_) => continue,
+                        Err(e @ Error::LimitExceeded(_)) => {
+                            stream.write_all(self.reject(&peer, e).as_bytes()).await?;
+                            break;
+                        }
+                        Err(e) => return Err(e),
                     }
-                    match command? {
-                        Command::Ping => {
-                            info!("Received ping");
-                            stream.try_write(b"PONG")?;
-                        },
-                        Command::Version => {
-                            info!("Received version");
-                            stream.try_write(b"yarad 0.1.0")?;
-                        },
-                        Command::Reload => {
-                            info!("recompiling rules");
-                            let config = config.lock().await;
-                            let rules_dir = config.get_rules_dir();
-                            let new_rules = compile_rules(rules_dir)?;
-                            let mut locked_rules = self.rules.lock().await;
-                            *locked_rules = new_rules;
-                            info!("recompilation done");
+                }
+                Err(Error::InvalidCommand(e)) => {
+                    warn!("Received invalid command: {}", e);
+                    stream.write_all(format!("Invalid command: {}\n", e).as_bytes()).await?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            handled += 1;
+            self.stats.request();
 
+            let mut close = false;
+            let reply = match command {
+                Ok(Command::IdSession) => {
+                    info!("session started by {}", peer);
+                    session = Some(0);
+                    continue;
+                }
+                Ok(Command::End) => break,
+                Ok(command) => match self.limiter.check_request(&peer) {
+                    Ok(()) => match self.execute(command, &mut stream, &mut buf, &peer).await {
+                        Ok(reply) => reply,
+                        Err(e @ Error::LimitExceeded(_)) => {
+                            // the rest of the stream can not be consumed any more
+                            close = true;
+                            self.reject(&peer, e)
                         }
-                        Command::Scan(path) => {
-                            info!("Received scan request for {}", path);
-                            match self.scan(path).await {
-                                Ok(results) => {
-                                    for result in results {
-                                        for rule in result.rule {
-                                            info!("{}: {}", rule, result.path);
-                                            let message = format!("{}: {}\n", rule, result.path);
-                                            stream.try_write(message.as_bytes())?;
-                                        }
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("Error while scanning: {}", e);
-                                    stream.try_write(format!("Error while scanning: {}\n", e).as_bytes())?;
-                                }
-
-                            }
-                        },
-                        _ => Err(Error::InvalidCommand("Invalid command".to_string()))?,
+                        Err(e) => return Err(e),
+                    },
+                    Err(e) => self.reject(&peer, e),
+                },
+                Err(Error::InvalidCommand(e)) => {
+                    let message = format!("Invalid command: {}\n", e);
+                    warn!("Received {}", message);
+                    message
+                }
+                Err(e) => return Err(e),
+            };
+
+            match session.as_mut() {
+                Some(id) => {
+                    *id += 1;
+                    let framed = reply
+                        .lines()
+                        .chain(std::iter::once(SESSION_DONE))
+                        .map(|line| format!("{}: {}\n", id, line))
+                        .collect::<String>();
+                    stream.write_all(framed.as_bytes()).await?;
+                }
+                None => stream.write_all(reply.as_bytes()).await?,
+            }
+            if close {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn execute(&self, command: Command, stream: &mut Stream, buf: &mut Vec<u8>, peer: &Peer) -> Result<String> {
+        if command.is_admin() && !self.is_admin(peer) {
+            warn!("refused {:?} from {}", command, peer);
+            return Ok(format!("Not authorized: {} may not run {:?}\n", peer, command));
+        }
+        Ok(match command {
+            Command::Ping => {
+                info!("Received ping");
+                "PONG\n".to_string()
+            },
+            Command::Version => {
+                info!("Received version");
+                "yarad 0.1.0\n".to_string()
+            },
+            Command::Reload => match self.reload().await {
+                Ok(restart_required) if restart_required.is_empty() => "RELOADED\n".to_string(),
+                Ok(restart_required) => format!("RELOADED\n{}{}\n", RESTART_REQUIRED_PREFIX, restart_required.join(", ")),
+                Err(e) => {
+                    error!("Error while reloading: {}", e);
+                    format!("Error while reloading: {}\n", e)
+                }
+            },
+            Command::Shutdown => {
+                warn!("shutdown requested by {}", peer);
+                self.shutdown.notify_one();
+                "SHUTDOWN\n".to_string()
+            }
+            Command::Stats => {
+                info!("Received stats");
+                let rules = self.rules.lock().await.rules().len();
+                self.stats.report(rules)
+            }
+            Command::Status => {
+                info!("Received status");
+                let rules = self.rules.lock().await;
+                let mut reply = format!("RULES: {}\n", rules.rules().len());
+                for file in rules.skipped() {
+                    reply.push_str(&skipped_line(file));
+                }
+                reply
+            }
+            Command::RulesList(namespace) => {
+                info!("Received rules list");
+                let rules = self.rules.lock().await;
+                let mut reply = String::new();
+                for rule in rules.rules() {
+                    if namespace.as_ref().map_or(true, |n| *n == rule.namespace) {
+                        reply.push_str(&serde_json::to_string(rule)?);
+                        reply.push('\n');
+                    }
+                }
+                reply
+            }
+            Command::RulesShow(name) => {
+                info!("Received rules show {}", name);
+                let rules = self.rules.lock().await;
+                let mut reply = String::new();
+                for rule in rules.rules().iter().filter(|r| r.identifier == name || r.name() == name) {
+                    reply.push_str(&serde_json::to_string(rule)?);
+                    reply.push('\n');
+                }
+                if reply.is_empty() {
+                    reply = format!("Rule not found: {}\n", name);
+                }
+                reply
+            }
+            Command::RuleDisable(name) => {
+                if !self.rules.lock().await.rules().iter().any(|r| r.name() == name) {
+                    return Ok(format!("Rule not found: {}\n", name));
+                }
+                warn!("rule {} disabled by {}", name, peer);
+                match self.disabled.disable(&name) {
+                    Ok(()) => format!("DISABLED: {}\n", name),
+                    Err(e) => format!("Error while disabling: {}\n", e),
+                }
+            }
+            Command::RuleEnable(name) => {
+                warn!("rule {} enabled by {}", name, peer);
+                match self.disabled.enable(&name) {
+                    Ok(()) => format!("ENABLED: {}\n", name),
+                    Err(e) => format!("Error while enabling: {}\n", e),
+                }
+            }
+            Command::RuleListDisabled => {
+                info!("Received rule list-disabled");
+                self.disabled.list().iter().map(|name| format!("{}\n", name)).collect()
+            }
+            Command::RulesInfo => {
+                info!("Received rules info");
+                let info = self.rules.lock().await.info();
+                format!("{}\n", serde_json::to_string(&info)?)
+            }
+            Command::LogLevel(level) => {
+                warn!("log level change to {} requested by {}", level, peer);
+                match crate::log::set_log_level(&level) {
+                    Ok(level) => format!("LOGLEVEL: {}\n", level),
+                    Err(_) => format!("Invalid command: invalid log level {}\n", level),
+                }
+            }
+            Command::Scan(path, options) | Command::ContScan(path, options) | Command::MultiScan(path, options) => {
+                info!("Received scan request for {}", path);
+                let _permit = match self.limiter.begin_scan(peer) {
+                    Ok(permit) => permit,
+                    Err(e) => return Ok(self.reject(peer, e)),
+                };
+                let start = Instant::now();
+                let results = match self.scan(path.clone(), &options).await {
+                    Ok(results) => self.apply_policy(results, true).await.map(|results| {
+                        self.report(peer, &path, &results, start.elapsed());
+                        results
+                    }),
+                    Err(e) => Err(e),
+                };
+                self.scan_reply(peer, results)
+            },
+            Command::Instream(options) => {
+                info!("Received instream from {}", peer);
+                let data = self.read_instream(stream, buf, peer).await?;
+                let _permit = match self.limiter.begin_scan(peer) {
+                    Ok(permit) => permit,
+                    Err(e) => return Ok(self.reject(peer, e)),
+                };
+                let start = Instant::now();
+                let results = match self.scan_bytes(&data, &options).await {
+                    Ok(result) => self.apply_policy(vec![result], false).await.map(|results| {
+                        self.report(peer, INSTREAM_PATH, &results, start.elapsed());
+                        results
+                    }),
+                    Err(e) => Err(e),
+                };
+                self.scan_reply(peer, results)
+            }
+            Command::QuarantineList => {
+                info!("Received quarantine list");
+                match self.quarantine.list() {
+                    Ok(entries) => {
+                        let mut reply = String::new();
+                        for entry in entries {
+                            reply.push_str(&serde_json::to_string(&entry)?);
+                            reply.push('\n');
+                        }
+                        reply
                     }
+                    Err(e) => quarantine_error(e),
+                }
+            }
+            Command::QuarantineRestore(id) => {
+                info!("Received quarantine restore {}", id);
+                match self.quarantine.restore(&id) {
+                    Ok(entry) => format!("RESTORED: {}\n", entry.original_path),
+                    Err(e) => quarantine_error(e),
+                }
+            }
+            Command::QuarantineDelete(id) => {
+                info!("Received quarantine delete {}", id);
+                match self.quarantine.delete(&id) {
+                    Ok(()) => format!("DELETED: {}\n", id),
+                    Err(e) => quarantine_error(e),
                 }
             }
-        }).await?;
+            other => format!("Invalid command: {:?} is not supported\n", other),
+        })
+    }
 
-        main_loop?;
-        Ok(())
+    /// Whether `peer` may run the admin commands: root, the daemon's user and `admin_uids`.
+    /// Peers over TCP never do, having no uid.
+    fn is_admin(&self, peer: &Peer) -> bool {
+        match peer {
+            Peer::Uid(uid) => *uid == 0 || *uid == getuid().as_raw() || self.config().get_admin_uids().contains(uid),
+            _ => false,
+        }
     }
 
-    pub fn daemonize(&self) -> Result<()> {
-        let username = self.get_config().get_user();
-        let as_su = geteuid().is_root();
-    
+    /// The matches of the namespaces selected by `options`, without the disabled rules.
+    fn select(&self, matches: Vec<RuleMatch>, options: &ScanOptions) -> Vec<RuleMatch> {
+        matches
+            .into_iter()
+            .filter(|m| options.selects(&m.namespace) && !self.disabled.contains(m))
+            .collect()
+    }
+
+    /// Run the configured actions on the matched `results`. `is_file` is false for scanned data.
+    /// The actions block on hooks and file operations, so they run off the runtime's threads.
+    async fn apply_policy(&self, mut results: Vec<ScanResult>, is_file: bool) -> Result<Vec<ScanResult>> {
+        if !results.iter().any(|r| r.is_infected()) {
+            return Ok(results);
+        }
+        let config = self.config();
+        let quarantine = self.quarantine.clone();
+        let results = tokio::task::spawn_blocking(move || {
+            let on_match = *config.get_quarantine().get_on_match();
+            for result in results.iter_mut() {
+                action::apply(config.get_policy(), &quarantine, on_match, result, is_file);
+            }
+            results
+        })
+        .await?;
+        Ok(results)
+    }
+
+    /// Send the finished scan of `target` to the event log and the webhook.
+    fn report(&self, peer: &Peer, target: &str, results: &[ScanResult], duration: Duration) {
+        let peer = peer.to_string();
+        self.events.scan(&peer, target, results, duration);
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(&peer, results);
+        }
+    }
+
+    fn scan_reply(&self, peer: &Peer, results: Result<Vec<ScanResult>>) -> String {
+        match results {
+            Ok(results) => {
+                let mut reply = String::new();
+                for result in results {
+                    for rule in result.rule {
+                        info!("{}: {}", rule, result.path);
+                        reply.push_str(&format!("{}: {}\n", rule, result.path));
+                    }
+                    for action in result.actions {
+                        reply.push_str(&format!("{}{}: {}\n", ACTION_PREFIX, action, result.path));
+                    }
+                    if let Some(error) = result.error {
+                        warn!("failed to scan {}: {}", result.path, error);
+                        self.stats.error();
+                        reply.push_str(&error_line(&error, &result.path));
+                    }
+                }
+                reply
+            },
+            Err(e @ Error::LimitExceeded(_)) => self.reject(peer, e),
+            Err(e) => {
+                error!("Error while scanning: {}", e);
+                self.stats.error();
+                format!("Error while scanning: {}\n", e)
+            }
+        }
+    }
+
+    /// Read the chunks following `INSTREAM` up to the terminating zero length chunk.
+    async fn read_instream(&self, stream: &mut Stream, buf: &mut Vec<u8>, peer: &Peer) -> Result<Vec<u8>> {
+        let max = *self.config().get_stream_max_length() as usize;
+        let mut data = Vec::new();
+        loop {
+            while buf.len() < 4 {
+                if self.fill(stream, buf, peer).await? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+            let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if len == 0 {
+                buf.drain(..4);
+                return Ok(data);
+            }
+            if data.len() + len > max {
+                return Err(Error::LimitExceeded("stream max length"));
+            }
+            while buf.len() < 4 + len {
+                if self.fill(stream, buf, peer).await? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+            data.extend_from_slice(&buf[4..4 + len]);
+            buf.drain(..4 + len);
+        }
+    }
+
+    async fn fill(&self, stream: &mut Stream, buf: &mut Vec<u8>, peer: &Peer) -> Result<usize> {
+        let n = stream.read_buf(buf).await?;
+        self.limiter.check_bytes(peer, n)?;
+        Ok(n)
+    }
+
+    fn reject(&self, peer: &Peer, e: Error) -> String {
+        warn!("Rejected request from {}: {}", peer, e);
+        self.stats.rejected();
+        format!("{}\n", e)
+    }
+
+    /// Detach from the terminal, with the pid file and the stdout and stderr logs at the
+    /// configured paths. Privileges are dropped later, once the listener is open.
+    pub fn daemonize(&self) -> Result<PidFile> {
+        let config = self.config();
+
         let open_opts = OpenOptions::new()
-            .truncate(false)
+            .append(true)
             .create(true)
-            .write(true)
             .to_owned();
-    
-        let workdir = self.get_config().get_working_dir();
-        let working_directory = if Path::new(workdir).exists() {
-            workdir
-        } else {
-            create_dir(workdir)?;
-            workdir
-        };
-    
-        let (stdout, stderr, pid_file) = if as_su {
-            (
-                Some(open_opts.open("/var/log/yarad.out")?),
-                Some(open_opts.open("/var/log/yarad.log")?),
-                "/var/run/yarad/yarad.pid",
-            )
-       } else {
-            (None, None, "yarad.pid")
-        };
-    
-        open_opts.open(pid_file)?;
-    
-        let daemonize = Daemonize::new()
-            .user(User::from(&username[..]))
+
+        // created by `new`
+        let workdir = config.get_working_dir();
+
+        let pid_file = config.get_pid_file();
+        PidFile::check(pid_file)?;
+        let stdout = open_opts.open(config.get_stdout_log())?;
+        let stderr = open_opts.open(config.get_stderr_log())?;
+
+        Daemonize::new()
             .pid_file(pid_file)
-            .chown_pid_file(false)
-            .working_directory(working_directory);
-    
-        if stdout.is_some() {
-            daemonize
-                .stdout(stdout.unwrap())
-                .stderr(stderr.unwrap())
-                .start()?;
-        } else {
-            daemonize.start()?;
-        }
-    
-        info!("yarad started by {}", username);
-        Ok(())
+            .working_directory(workdir)
+            .stdout(stdout)
+            .stderr(stderr)
+            .start()?;
+
+        info!("yarad daemonized");
+        Ok(PidFile::adopt(pid_file))
     }
 }
 
-fn compile_rules(rules_dir: &str) -> Result<Rules> {
-    let rule_files = WalkDir::new(rules_dir)
-        .into_iter()
-        .filter_map(|f| f.ok())
-        .filter(|f| f.path().extension().unwrap_or_default() == "yar" || f.path().extension().unwrap_or_default() == "yara")
-        .filter(|f| f.file_type().is_file())
-        .collect::<Vec<_>>();
-
-    let compiler = rule_files.into_iter().try_fold(Compiler::new()?, |compiler, f| {
-        compiler.add_rules_file(f.path())
-    })?;
-
+/// The variables set for `file`, with `overrides` from the request taking precedence.
+fn file_externals(file: &Path, overrides: &Externals) -> Externals {
+    let mut externals = rule::file_externals(file);
+    externals.extend(overrides.clone());
+    externals
+}
 
-    Ok(compiler.compile_rules()?)
+fn quarantine_error(e: Error) -> String {
+    error!("Quarantine error: {}", e);
+    format!("Quarantine error: {}\n", e)
 }
@@ -0,0 +1,203 @@
+use crate::config::{Policy, PolicyAction};
+use crate::error::*;
+use crate::scan::{FileId, RuleMatch, ScanResult};
+use super::quarantine::Quarantine;
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::fcntl::{openat, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{unlinkat, UnlinkatFlags};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long an `exec` command may run before it is killed.
+const EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether `policy` selects `rule`. Every non empty selector has to hold. Rules are selected by
+/// name alone or qualified with their namespace.
+fn selects(policy: &Policy, rule: &RuleMatch) -> bool {
+    (policy.get_rules().is_empty()
+        || policy.get_rules().contains(&rule.identifier)
+        || policy.get_rules().contains(&rule.name()))
+        && (policy.get_tags().is_empty() || rule.tags.iter().any(|t| policy.get_tags().contains(t)))
+        && policy
+            .get_metadata()
+            .iter()
+            .all(|(k, v)| rule.metadata.get(k) == Some(v))
+}
+
+/// Run the actions of the policies selecting any rule matched in `result`, recording the outcome
+/// of each in `result.actions`. With `on_match`, every match is also quarantined. Only `log`
+/// and `exec` apply to results without a file, e.g. `INSTREAM`.
+pub fn apply(policies: &[Policy], quarantine: &Quarantine, on_match: bool, result: &mut ScanResult, is_file: bool) {
+    if !result.is_infected() {
+        return;
+    }
+
+    let mut actions = BTreeSet::new();
+    for policy in policies {
+        if result.details.iter().any(|rule| selects(policy, rule)) {
+            for action in policy.get_actions() {
+                let command = match action {
+                    PolicyAction::Exec => policy.get_command().clone(),
+                    _ => None,
+                };
+                actions.insert((*action, command));
+            }
+        }
+    }
+    if on_match {
+        actions.insert((PolicyAction::Quarantine, None));
+    }
+
+    // the file is gone after a successful quarantine or delete
+    let mut removed = false;
+    for (action, command) in actions {
+        let outcome = match action {
+            PolicyAction::Log => {
+                warn!("{} matched {}", result.path, result.matches().cloned().collect::<Vec<_>>().join(", "));
+                Ok(String::new())
+            }
+            PolicyAction::Exec => exec(command.as_deref().unwrap_or_default(), result),
+            _ if !is_file || removed => continue,
+            PolicyAction::Chmod => Target::open(&result.path, result.file)
+                .and_then(|target| target.chmod(0o000))
+                .map(|_| String::new()),
+            PolicyAction::Quarantine => {
+                let rules = result.matches().cloned().collect::<Vec<_>>();
+                Target::open(&result.path, result.file)
+                    .and_then(|target| quarantine.quarantine(&target, &rules))
+                    .map(|entry| format!(" {}", entry.id))
+            }
+            PolicyAction::Delete => Target::open(&result.path, result.file)
+                .and_then(|target| target.remove())
+                .map(|_| String::new()),
+        };
+        match outcome {
+            Ok(detail) => {
+                info!("{} {}", action.as_str(), result.path);
+                if matches!(action, PolicyAction::Quarantine | PolicyAction::Delete) {
+                    removed = true;
+                }
+                result.actions.push(format!("{} OK{}", action.as_str(), detail));
+            }
+            Err(e) => {
+                error!("Failed to {} {}: {}", action.as_str(), result.path, e);
+                result.actions.push(format!("{} FAILED", action.as_str()));
+            }
+        }
+    }
+}
+
+/// A matched file opened to act on: its directory and the file itself, checked to be the one
+/// scanned. It is not looked up by its path again and symlinks are not followed, so replacing a
+/// directory on the path after the scan can't redirect the action to another file.
+#[derive(Debug)]
+pub struct Target {
+    dir: File,
+    name: OsString,
+    pub file: File,
+    /// as given to the scan
+    pub path: PathBuf,
+}
+
+impl Target {
+    pub fn open(path: &str, scanned: Option<FileId>) -> Result<Self> {
+        let failed = |reason: String| Error::ActionFailed {
+            action: "open".to_string(),
+            reason,
+        };
+        let path = Path::new(path);
+        let name = path
+            .file_name()
+            .ok_or_else(|| failed(format!("{} is not a file", path.display())))?
+            .to_os_string();
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        let dir = OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY).open(parent)?;
+        let flags = OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC;
+        let fd = openat(dir.as_raw_fd(), name.as_os_str(), flags, Mode::empty()).map_err(|e| match e {
+            Errno::ELOOP => failed(format!("{} is a symlink", path.display())),
+            e => e.into(),
+        })?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        let metadata = file.metadata()?;
+        if !metadata.is_file() || scanned != Some(FileId::from(&metadata)) {
+            return Err(failed(format!("{} is not the scanned file any more", path.display())));
+        }
+        Ok(Target {
+            dir,
+            name,
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Unlink the file from the directory it was opened in.
+    pub fn remove(&self) -> Result<()> {
+        unlinkat(Some(self.dir.as_raw_fd()), self.name.as_os_str(), UnlinkatFlags::NoRemoveDir)?;
+        Ok(())
+    }
+
+    /// Change the mode of the opened file.
+    pub fn chmod(&self, mode: u32) -> Result<()> {
+        self.file.set_permissions(Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    /// The path of the file in the directory actually opened, without symlinks.
+    pub fn real_path(&self) -> PathBuf {
+        fs::read_link(format!("/proc/self/fd/{}", self.dir.as_raw_fd()))
+            .map(|dir| dir.join(&self.name))
+            .unwrap_or_else(|_| self.path.clone())
+    }
+}
+
+/// Run `command` with the JSON report of `result` on its stdin.
+fn exec(command: &str, result: &ScanResult) -> Result<String> {
+    let report = serde_json::to_vec(result)?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // the command may not read its input at all
+        if let Err(e) = stdin.write_all(&report) {
+            warn!("Failed to write the report to `{}`: {}", command, e);
+        }
+    }
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > EXEC_TIMEOUT {
+            child.kill()?;
+            child.wait()?;
+            return Err(Error::ActionFailed {
+                action: "exec".to_string(),
+                reason: format!("`{}` timed out", command),
+            });
+        }
+        sleep(Duration::from_millis(50));
+    };
+
+    if status.success() {
+        Ok(String::new())
+    } else {
+        Err(Error::ActionFailed {
+            action: "exec".to_string(),
+            reason: format!("`{}` exited with {}", command, status),
+        })
+    }
+}
@@ -10,12 +10,22 @@ pub enum Command {
     /// show daemon pid
     #[clap(name = "pid")]
     Pid,
-    /// reload config
+    /// reload the running daemon
     #[clap(name = "reload")]
     Reload,
+    /// stop the running daemon
+    #[clap(name = "stop")]
+    Stop,
+    /// show whether the daemon is running
+    #[clap(name = "status")]
+    Status,
     /// show rules directory path
     #[clap(name = "show-rules-dir")]
     ShowRulesDir,
+    /// scan worker started by the daemon
+    #[clap(name = "worker")]
+    #[value(hide = true)]
+    Worker,
 }
 
 #[derive(Debug, Parser, Tia)]
@@ -28,6 +38,12 @@ pub struct Args {
     /// daemon start
     #[clap(long)]
     foreground: bool,
+    /// seconds `stop` waits for the daemon to exit
+    #[clap(long, default_value_t = 10)]
+    timeout: u64,
+    /// SIGKILL the daemon if it does not stop within the timeout
+    #[clap(long)]
+    kill: bool,
     /// command
     #[clap(value_enum)]
     command: Option<Command>,
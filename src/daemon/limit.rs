@@ -0,0 +1,169 @@
+use crate::config::Limits;
+use crate::error::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Identity of a connected client. Quotas are accounted per peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Uid(u32),
+    Ip(IpAddr),
+    Unknown,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Uid(uid) => write!(f, "uid {}", uid),
+            Peer::Ip(ip) => write!(f, "{}", ip),
+            Peer::Unknown => write!(f, "unknown peer"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    count: u64,
+}
+
+impl Window {
+    fn new() -> Self {
+        Window {
+            start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Add `n` to the window and return whether the total stays within `max`.
+    fn add(&mut self, n: u64, max: u64, period: Duration) -> bool {
+        if self.start.elapsed() >= period {
+            self.start = Instant::now();
+            self.count = 0;
+        }
+        if self.count + n > max {
+            return false;
+        }
+        self.count += n;
+        true
+    }
+}
+
+#[derive(Debug)]
+struct PeerState {
+    scans: usize,
+    requests: Window,
+    bytes: Window,
+}
+
+impl PeerState {
+    fn new() -> Self {
+        PeerState {
+            scans: 0,
+            requests: Window::new(),
+            bytes: Window::new(),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.scans == 0
+            && self.requests.start.elapsed() >= Duration::from_secs(1)
+            && self.bytes.start.elapsed() >= Duration::from_secs(60)
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RwLock<Limits>,
+    peers: Mutex<HashMap<Peer, PeerState>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        RateLimiter {
+            limits: RwLock::new(limits),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the limits, e.g. on reload. Accounting of the current windows is kept.
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.write().unwrap_or_else(|e| e.into_inner()) = limits;
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn max_files_per_scan(&self) -> Option<usize> {
+        *self.limits().get_max_files_per_scan()
+    }
+
+    fn with_peer<T>(&self, peer: &Peer, f: impl FnOnce(&mut PeerState) -> T) -> T {
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        peers.retain(|p, state| p == peer || !state.is_idle());
+        f(peers.entry(peer.clone()).or_insert_with(PeerState::new))
+    }
+
+    /// Account one request from `peer`.
+    pub fn check_request(&self, peer: &Peer) -> Result<()> {
+        let max = match self.limits().get_max_requests_per_second() {
+            Some(max) => *max as u64,
+            None => return Ok(()),
+        };
+        if self.with_peer(peer, |state| state.requests.add(1, max, Duration::from_secs(1))) {
+            Ok(())
+        } else {
+            Err(Error::LimitExceeded("max requests per second"))
+        }
+    }
+
+    /// Account `n` bytes received from `peer`.
+    pub fn check_bytes(&self, peer: &Peer, n: usize) -> Result<()> {
+        let max = match self.limits().get_max_stream_bytes_per_minute() {
+            Some(max) => *max,
+            None => return Ok(()),
+        };
+        if self.with_peer(peer, |state| state.bytes.add(n as u64, max, Duration::from_secs(60))) {
+            Ok(())
+        } else {
+            Err(Error::LimitExceeded("max bytes streamed per minute"))
+        }
+    }
+
+    /// Reserve a scan slot for `peer`. The slot is released when the permit is dropped.
+    pub fn begin_scan(&self, peer: &Peer) -> Result<ScanPermit<'_>> {
+        let max = *self.limits().get_max_concurrent_scans();
+        let acquired = self.with_peer(peer, |state| {
+            if max.map_or(false, |max| state.scans >= max) {
+                false
+            } else {
+                state.scans += 1;
+                true
+            }
+        });
+        if acquired {
+            Ok(ScanPermit {
+                limiter: self,
+                peer: peer.clone(),
+            })
+        } else {
+            Err(Error::LimitExceeded("max concurrent scans"))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ScanPermit<'a> {
+    limiter: &'a RateLimiter,
+    peer: Peer,
+}
+
+impl Drop for ScanPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.with_peer(&self.peer, |state| state.scans -= 1);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::error::*;
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Process name of a running daemon, as in `/proc/<pid>/comm`.
+const PROCESS_NAME: &str = "yarad";
+
+/// The pid file of the running daemon, removed when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the current pid to `path`, for the foreground mode where `daemonize` does not.
+    pub fn create<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile { path })
+    }
+
+    /// Take over the pid file written by `daemonize`.
+    pub fn adopt<P: Into<PathBuf>>(path: P) -> Self {
+        PidFile { path: path.into() }
+    }
+
+    /// Fail if `path` records a running yarad. A stale pid file is removed.
+    pub fn check<P: AsRef<Path>>(path: P) -> Result<()> {
+        let path = path.as_ref();
+        match read_pid(path)? {
+            Some(pid) if is_running(pid) => Err(Error::AlreadyRunning(pid)),
+            Some(pid) => {
+                warn!("removing stale pid file {} of {}", path.display(), pid);
+                fs::remove_file(path)?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // leave it alone when another process took it over
+        match read_pid(&self.path) {
+            Ok(Some(pid)) if pid == std::process::id() as i32 => match fs::remove_file(&self.path) {
+                Ok(()) => info!("removed pid file {}", self.path.display()),
+                Err(e) => warn!("failed to remove pid file {}: {}", self.path.display(), e),
+            },
+            Ok(_) => {}
+            Err(e) => warn!("failed to read pid file {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// The pid recorded in `path`, `None` when there is no pid file or it is empty.
+pub fn read_pid<P: AsRef<Path>>(path: P) -> Result<Option<i32>> {
+    let content = match fs::read_to_string(path.as_ref()) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let content = content.trim();
+    if content.is_empty() {
+        return Ok(None);
+    }
+    content
+        .parse()
+        .map(Some)
+        .map_err(|_| Error::InvalidPidFile(path.as_ref().display().to_string()))
+}
+
+/// Whether `pid` is a live yarad process, so that a recycled pid is not mistaken for the daemon.
+pub fn is_running(pid: i32) -> bool {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|comm| comm.trim() == PROCESS_NAME)
+        .unwrap_or(false)
+}
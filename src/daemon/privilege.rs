@@ -0,0 +1,132 @@
+//! Privilege model: yarad starts as root, opens what needs root, then switches to the configured
+//! user and group keeping only the capabilities below.
+
+use crate::error::*;
+use caps::{CapSet, Capability, CapsHashSet};
+use log::{info, warn};
+use nix::unistd::{chown, geteuid, setgid, setgroups, setuid, Group, User};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Capabilities kept after dropping root, and why.
+const RETAINED: [(Capability, &str); 1] = [(Capability::CAP_DAC_READ_SEARCH, "scanning files of other users")];
+
+/// Also kept when matched files are quarantined, deleted or chmodded. `QUARANTINE RESTORE` needs
+/// them as well.
+const FILE_ACTIONS: [(Capability, &str); 3] = [
+    (Capability::CAP_DAC_OVERRIDE, "moving and deleting files of other users"),
+    (Capability::CAP_FOWNER, "changing the mode of files of other users"),
+    (Capability::CAP_CHOWN, "restoring the owner of quarantined files"),
+];
+
+/// The capabilities to keep, with `modifies_files` when the policies change matched files.
+pub fn retained(modifies_files: bool) -> Vec<(Capability, &'static str)> {
+    let mut retained = RETAINED.to_vec();
+    if modifies_files {
+        retained.extend_from_slice(&FILE_ACTIONS);
+    }
+    retained
+}
+
+/// Switch to `user` and `group`, keeping only the `retained` capabilities. Does nothing but
+/// check the capabilities when not running as root, e.g. after a restart of the main loop.
+/// `workers` passes `CAP_DAC_READ_SEARCH` on to the scan workers through the ambient set.
+pub fn drop_privileges(user: &str, group: &str, retained: &[(Capability, &str)], workers: bool) -> Result<()> {
+    if !geteuid().is_root() {
+        check_capabilities(retained)?;
+        return Ok(());
+    }
+    let user = User::from_name(user)?.ok_or_else(|| Error::UserNameError {
+        reason: format!("user {} not found", user),
+    })?;
+    let group = Group::from_name(group)?.ok_or_else(|| Error::UserNameError {
+        reason: format!("group {} not found", group),
+    })?;
+
+    let capabilities = retained.iter().map(|(cap, _)| *cap).collect::<CapsHashSet>();
+    // only possible while CAP_SETPCAP is still effective
+    for cap in caps::all().difference(&capabilities) {
+        caps::drop(None, CapSet::Bounding, *cap)?;
+    }
+    caps::securebits::set_keepcaps(true)?;
+    setgroups(&[group.gid])?;
+    setgid(group.gid)?;
+    setuid(user.uid)?;
+    caps::securebits::set_keepcaps(false)?;
+
+    caps::set(None, CapSet::Permitted, &capabilities)?;
+    caps::set(None, CapSet::Effective, &capabilities)?;
+    caps::clear(None, CapSet::Inheritable)?;
+    caps::clear(None, CapSet::Ambient)?;
+    if workers {
+        caps::raise(None, CapSet::Inheritable, Capability::CAP_DAC_READ_SEARCH)?;
+        caps::raise(None, CapSet::Ambient, Capability::CAP_DAC_READ_SEARCH)?;
+    }
+    info!("running as {}:{}", user.name, group.name);
+
+    check_capabilities(retained)
+}
+
+/// Warn about each retained capability missing from the effective set. Returns an error only
+/// when the capabilities can not be read.
+pub fn check_capabilities(retained: &[(Capability, &str)]) -> Result<()> {
+    for (cap, reason) in retained {
+        if !caps::has_cap(None, CapSet::Effective, *cap)? {
+            warn!("{} is missing, needed for {}", cap, reason);
+        }
+    }
+    Ok(())
+}
+
+/// Hand `path` created while still root over to `user`.
+pub fn chown_to_user<P: AsRef<Path>>(path: P, user: &str) -> Result<()> {
+    if !geteuid().is_root() {
+        return Ok(());
+    }
+    match User::from_name(user)? {
+        Some(user) => chown(path.as_ref(), Some(user.uid), Some(user.gid))?,
+        None => warn!("user {} not found, {} is owned by root", user, path.as_ref().display()),
+    }
+    Ok(())
+}
+
+/// Create `dir` owned by `user` when it is missing. An existing one is left as it is, as it may
+/// be shared, with a warning when it belongs to someone else.
+pub fn create_dir_for_user<P: AsRef<Path>>(dir: P, user: &str) -> Result<()> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        fs::create_dir_all(dir)?;
+        return chown_to_user(dir, user);
+    }
+    if !owned_by(dir, user)? {
+        warn!("{} is not owned by {}, who may not be able to write it", dir.display(), user);
+    }
+    Ok(())
+}
+
+/// Like `create_dir_for_user`, but an existing `dir` has to belong to `user` already, for a
+/// directory yarad needs to itself, e.g. to rotate files in.
+pub fn dedicated_dir<P: AsRef<Path>>(dir: P, user: &str) -> Result<()> {
+    let dir = dir.as_ref();
+    if dir.exists() && !owned_by(dir, user)? {
+        return Err(Error::NoPermission(format!(
+            "{}, which has to be a directory of its own owned by {}",
+            dir.display(),
+            user
+        )));
+    }
+    create_dir_for_user(dir, user)
+}
+
+/// Whether `path` belongs to `user`. Always true when not running as root, as nothing is
+/// handed over then.
+fn owned_by(path: &Path, user: &str) -> Result<bool> {
+    if !geteuid().is_root() {
+        return Ok(true);
+    }
+    Ok(match User::from_name(user)? {
+        Some(user) => fs::metadata(path)?.uid() == user.uid.as_raw(),
+        None => true,
+    })
+}
@@ -0,0 +1,194 @@
+use crate::error::*;
+use log::{info, warn};
+use nix::unistd::fchown;
+use super::action::Target;
+use super::privilege::chown_to_user;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY_SIZE: usize = 32;
+
+/// What is known about a quarantined file. Stored next to it as `<id>.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantineEntry {
+    pub id: String,
+    pub original_path: String,
+    pub owner: u32,
+    pub group: u32,
+    pub mode: u32,
+    pub sha256: String,
+    pub rules: Vec<String>,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Sidecar {
+    #[serde(flatten)]
+    entry: QuarantineEntry,
+    /// XOR key the content is stored with, so that it can't be executed or picked up by other
+    /// scanners while in quarantine.
+    key: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    dir: PathBuf,
+}
+
+impl Quarantine {
+    /// Open the quarantine directory, creating it owned by `user` when running as root.
+    pub fn new<P: Into<PathBuf>>(dir: P, user: &str) -> Result<Self> {
+        let dir = dir.into();
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+            fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
+            chown_to_user(&dir, user)?;
+        }
+        Ok(Quarantine { dir })
+    }
+
+    /// Move `target` into the quarantine.
+    pub fn quarantine(&self, target: &Target, rules: &[String]) -> Result<QuarantineEntry> {
+        let metadata = target.file.metadata()?;
+        let mut content = Vec::new();
+        (&target.file).read_to_end(&mut content)?;
+
+        let id = hex(&random_bytes(16)?);
+        let key = random_bytes(KEY_SIZE)?;
+        let entry = QuarantineEntry {
+            id: id.clone(),
+            original_path: target.real_path().display().to_string(),
+            owner: metadata.uid(),
+            group: metadata.gid(),
+            mode: metadata.mode() & 0o7777,
+            sha256: format!("{:x}", Sha256::digest(&content)),
+            rules: rules.to_vec(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        };
+
+        xor(&mut content, &key);
+        let data = self.dir.join(&id);
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o400)
+            .open(&data)?
+            .write_all(&content)?;
+        let sidecar = Sidecar {
+            entry: entry.clone(),
+            key: hex(&key),
+        };
+        if let Err(e) = fs::write(self.sidecar(&id), serde_json::to_vec_pretty(&sidecar)?) {
+            fs::remove_file(&data)?;
+            return Err(e.into());
+        }
+        target.remove()?;
+
+        info!("quarantined {} as {}", entry.original_path, id);
+        Ok(entry)
+    }
+
+    pub fn list(&self) -> Result<Vec<QuarantineEntry>> {
+        let mut entries = Vec::new();
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().unwrap_or_default() == "json" {
+                entries.push(read_sidecar(&path)?.entry);
+            }
+        }
+        entries.sort_by_key(|e| e.timestamp);
+        Ok(entries)
+    }
+
+    /// Put the file back at its original path with its original mode and owner.
+    pub fn restore(&self, id: &str) -> Result<QuarantineEntry> {
+        let sidecar = read_sidecar(&self.sidecar(check_id(id)?))?;
+        let entry = sidecar.entry;
+        let original = Path::new(&entry.original_path);
+        if original.exists() {
+            return Err(Error::ActionFailed {
+                action: "restore".to_string(),
+                reason: format!("{} already exists", entry.original_path),
+            });
+        }
+
+        let mut content = fs::read(self.dir.join(id))?;
+        xor(&mut content, &unhex(&sidecar.key)?);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(entry.mode)
+            .open(original)?;
+        file.write_all(&content)?;
+        // on the created file, the path could be redirected by now
+        file.set_permissions(Permissions::from_mode(entry.mode))?;
+        if let Err(e) = fchown(file.as_raw_fd(), Some(entry.owner.into()), Some(entry.group.into())) {
+            warn!("failed to restore the owner of {}: {}", entry.original_path, e);
+        }
+        self.delete(id)?;
+
+        info!("restored {} to {}", id, entry.original_path);
+        Ok(entry)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<()> {
+        let id = check_id(id)?;
+        fs::remove_file(self.dir.join(id))?;
+        fs::remove_file(self.sidecar(id))?;
+        info!("deleted {} from quarantine", id);
+        Ok(())
+    }
+
+    fn sidecar(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+fn read_sidecar(path: &Path) -> Result<Sidecar> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Ids are generated hex strings; anything else could escape the quarantine directory.
+fn check_id(id: &str) -> Result<&str> {
+    if !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(id)
+    } else {
+        Err(Error::InvalidCommand(format!("invalid quarantine id: {}", id)))
+    }
+}
+
+fn random_bytes(n: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; n];
+    File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn xor(data: &mut [u8], key: &[u8]) {
+    for (b, k) in data.iter_mut().zip(key.iter().cycle()) {
+        *b ^= k;
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| Error::InvalidCommand(format!("invalid quarantine key: {}", s)))
+        })
+        .collect()
+}
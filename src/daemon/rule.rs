@@ -1 +1,599 @@
+//! Loading the rules: the `.yar`/`.yara` sources in `rules_dir` are compiled into one rule set,
+//! cached in `working_dir` until a source or a file it includes changes, and prebuilt `.yarc`
+//! files in `rules_dir` are loaded as they are. Each subdirectory of `rules_dir` is compiled into
+//! its own namespace, named after its relative path, and each file directly in `rules_dir` into
+//! one named after the file.
+//! Optionally, each source is first compiled alone and left out when that fails; only when the
+//! remaining ones fail together is each checked with the ones accepted before it. The rules are
+//! compiled with the external variables of the config and the ones set for each file.
 
+use crate::config::{builtin_externals, Config, External, Externals};
+use crate::error::*;
+use crate::scan::{FileId, RuleMatch};
+use log::{debug, error, info, warn};
+use nix::unistd::{Uid, User};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+use yara::{Compiler, Rule, Rules, Scanner};
+
+/// File in `working_dir` listing the disabled rules.
+const DISABLED_RULES_FILE: &str = "disabled_rules";
+
+/// Extension of compiled rule files.
+pub const COMPILED_EXTENSION: &str = "yarc";
+
+/// Part of the cache key, as compiled rules only load into the libyara they were compiled with.
+fn yara_version() -> String {
+    format!(
+        "libyara {}.{}.{}, yarad {}",
+        yara_sys::YR_MAJOR_VERSION,
+        yara_sys::YR_MINOR_VERSION,
+        yara_sys::YR_MICRO_VERSION,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// A source left out of the rules because it failed to compile with the ones before it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkippedFile {
+    pub path: String,
+    pub error: String,
+}
+
+/// A loaded rule, as listed by `RULES LIST`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleInfo {
+    pub namespace: String,
+    pub identifier: String,
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+    /// the source declaring the rule, or the compiled file it was loaded from
+    pub source: Option<String>,
+}
+
+impl RuleInfo {
+    /// `namespace:identifier`, as reported in replies.
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.namespace, self.identifier)
+    }
+}
+
+/// The loaded rule set as reported by `RULES INFO`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RulesetInfo {
+    /// hash of the YARA version, the sources and the compiled files
+    pub fingerprint: String,
+    /// unix time the sources were compiled, possibly by an earlier run
+    pub compiled_at: u64,
+    pub rules: usize,
+    pub skipped: usize,
+}
+
+/// The loaded rules: the compiled sources and the prebuilt files. YARA can't merge compiled
+/// rules, so each is scanned in turn.
+pub struct RuleSet {
+    compiled: Vec<Rules>,
+    skipped: Vec<SkippedFile>,
+    /// every loaded rule, in the order of `compiled`
+    index: Vec<RuleInfo>,
+    fingerprint: String,
+    compiled_at: u64,
+    /// declared external variables with their defaults
+    externals: Externals,
+}
+
+impl RuleSet {
+    /// No rules, until the daemon loads them after dropping privileges.
+    pub fn empty() -> Self {
+        RuleSet {
+            compiled: Vec::new(),
+            skipped: Vec::new(),
+            index: Vec::new(),
+            fingerprint: String::new(),
+            compiled_at: 0,
+            externals: Externals::new(),
+        }
+    }
+
+    /// Compile the sources in `rules_dir`, or load them from the cache in `working_dir` when
+    /// none changed since, and load the prebuilt files. With `skip_broken_rules`, the sources
+    /// failing to compile are left out instead of failing the whole set.
+    pub fn load(config: &Config) -> Result<Self> {
+        let rules_dir = config.get_rules_dir();
+        let sources = rule_files(rules_dir, |ext| ext == "yar" || ext == "yara");
+        let mut compiled = Vec::new();
+        let mut skipped = Vec::new();
+        let mut index = Vec::new();
+        let externals = declared_externals(config);
+        let key = fingerprint(&sources, *config.get_skip_broken_rules(), &externals)?;
+        let mut hasher = Sha256::new();
+        hasher.update(&key);
+        let mut compiled_at = now();
+        if !sources.is_empty() {
+            let (rules, broken, at) = compile_cached(config, &sources, &key, &externals)?;
+            let declared = declared_rules(rules_dir, &sources)?;
+            if let Some(rules) = rules {
+                index.extend(rules.get_rules().iter().map(|rule| {
+                    let source = declared.get(&(rule.namespace.to_string(), rule.identifier.to_string()));
+                    rule_info(rule, source.cloned())
+                }));
+                compiled.push(rules);
+            }
+            skipped = broken;
+            compiled_at = at;
+        }
+        for file in rule_files(rules_dir, |ext| ext == COMPILED_EXTENSION) {
+            info!("loading compiled rules {}", file.display());
+            hasher.update(file.display().to_string());
+            hasher.update(fs::read(&file)?);
+            let rules = Rules::load_from_file(&file.display().to_string())?;
+            let source = file.display().to_string();
+            index.extend(rules.get_rules().iter().map(|rule| rule_info(rule, Some(source.clone()))));
+            compiled.push(rules);
+        }
+        Ok(RuleSet {
+            compiled,
+            skipped,
+            index,
+            fingerprint: hex(&hasher.finalize()),
+            compiled_at,
+            externals,
+        })
+    }
+
+    /// Sources left out by `skip_broken_rules`.
+    pub fn skipped(&self) -> &[SkippedFile] {
+        &self.skipped
+    }
+
+    /// Every loaded rule.
+    pub fn rules(&self) -> &[RuleInfo] {
+        &self.index
+    }
+
+    pub fn info(&self) -> RulesetInfo {
+        RulesetInfo {
+            fingerprint: self.fingerprint.clone(),
+            compiled_at: self.compiled_at,
+            rules: self.index.len(),
+            skipped: self.skipped.len(),
+        }
+    }
+
+    /// Parse the values of the external variables overridden by a request as the types of
+    /// their defaults.
+    pub fn parse_externals(&self, values: &BTreeMap<String, String>) -> Result<Externals> {
+        values
+            .iter()
+            .map(|(name, value)| {
+                let invalid = || Error::InvalidCommand(format!("invalid value of external variable {}: {}", name, value));
+                let value = match self.externals.get(name) {
+                    Some(External::Bool(_)) => External::Bool(value.parse().map_err(|_| invalid())?),
+                    Some(External::Integer(_)) => External::Integer(value.parse().map_err(|_| invalid())?),
+                    Some(External::String(_)) => External::String(value.clone()),
+                    None => return Err(Error::InvalidCommand(format!("unknown external variable {}", name))),
+                };
+                Ok((name.clone(), value))
+            })
+            .collect()
+    }
+
+    /// Scan with the external variables `externals` set on top of their defaults. Returns which
+    /// file was scanned as well, read from the one descriptor the rules scan.
+    pub fn scan_file<P: AsRef<Path>>(&self, path: P, timeout: i32, externals: &Externals) -> Result<(Vec<RuleMatch>, FileId)> {
+        let file = File::open(path.as_ref())?;
+        let id = FileId::from(&file.metadata()?);
+        let mut matches = Vec::new();
+        for rules in &self.compiled {
+            let mut scanner = new_scanner(rules, timeout, externals)?;
+            matches.extend(scanner.scan_fd(&file)?.iter().map(RuleMatch::from));
+        }
+        Ok((matches, id))
+    }
+
+    pub fn scan_mem(&self, data: &[u8], timeout: i32, externals: &Externals) -> Result<Vec<RuleMatch>> {
+        let mut matches = Vec::new();
+        for rules in &self.compiled {
+            let mut scanner = new_scanner(rules, timeout, externals)?;
+            matches.extend(scanner.scan_mem(data)?.iter().map(RuleMatch::from));
+        }
+        Ok(matches)
+    }
+}
+
+/// Rules muted at runtime, by `namespace:name`. Kept in `working_dir`, one per line, so they
+/// stay muted across reloads and restarts.
+#[derive(Debug)]
+pub struct DisabledRules {
+    path: PathBuf,
+    names: Mutex<BTreeSet<String>>,
+}
+
+impl DisabledRules {
+    /// None, until `read`.
+    pub fn new(working_dir: &str) -> Self {
+        DisabledRules {
+            path: Path::new(working_dir).join(DISABLED_RULES_FILE),
+            names: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Read the rules disabled by an earlier run.
+    pub fn read(&self) -> Result<()> {
+        let names = match fs::read_to_string(&self.path) {
+            Ok(content) => content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        *self.names() = names;
+        Ok(())
+    }
+
+    pub fn contains(&self, rule: &RuleMatch) -> bool {
+        self.names().contains(&rule.name())
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.names().iter().cloned().collect()
+    }
+
+    pub fn disable(&self, name: &str) -> Result<()> {
+        let mut names = self.names();
+        if names.insert(name.to_string()) {
+            self.save(&names)?;
+        }
+        Ok(())
+    }
+
+    pub fn enable(&self, name: &str) -> Result<()> {
+        let mut names = self.names();
+        if names.remove(name) {
+            self.save(&names)?;
+        }
+        Ok(())
+    }
+
+    fn names(&self) -> MutexGuard<'_, BTreeSet<String>> {
+        self.names.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn save(&self, names: &BTreeSet<String>) -> Result<()> {
+        let content = names.iter().map(|name| format!("{}\n", name)).collect::<String>();
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, content)?;
+        fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+}
+
+/// The variables set for each scanned file and the ones in the config, with their defaults.
+fn declared_externals(config: &Config) -> Externals {
+    let mut externals = builtin_externals();
+    externals.extend(config.get_externals().clone());
+    externals
+}
+
+/// The variables set for each scanned file.
+pub fn file_externals(path: &Path) -> Externals {
+    let mut externals = Externals::new();
+    let mut set = |name: &str, value: String| externals.insert(name.to_string(), External::String(value));
+    set("filename", path.file_name().unwrap_or_default().to_string_lossy().to_string());
+    set("filepath", path.display().to_string());
+    set("extension", path.extension().unwrap_or_default().to_string_lossy().to_string());
+    if let Ok(metadata) = fs::metadata(path) {
+        let owner = match User::from_uid(Uid::from_raw(metadata.uid())) {
+            Ok(Some(user)) => user.name,
+            _ => metadata.uid().to_string(),
+        };
+        set("owner", owner);
+        externals.insert("filesize".to_string(), External::Integer(metadata.len() as i64));
+    }
+    externals
+}
+
+fn define(compiler: Compiler, name: &str, value: &External) -> Result<Compiler> {
+    Ok(match value {
+        External::Bool(b) => compiler.define_variable(name, *b)?,
+        External::Integer(i) => compiler.define_variable(name, *i)?,
+        External::String(s) => compiler.define_variable(name, s.as_str())?,
+    })
+}
+
+/// A compiler with the external variables declared.
+fn new_compiler(externals: &Externals) -> Result<Compiler> {
+    externals
+        .iter()
+        .try_fold(Compiler::new()?, |compiler, (name, value)| define(compiler, name, value))
+}
+
+fn new_scanner<'r>(rules: &'r Rules, timeout: i32, externals: &Externals) -> Result<Scanner<'r>> {
+    let mut scanner = rules.scanner()?;
+    scanner.set_timeout(timeout);
+    for (name, value) in externals {
+        let defined = match value {
+            External::Bool(b) => scanner.define_variable(name, *b),
+            External::Integer(i) => scanner.define_variable(name, *i),
+            External::String(s) => scanner.define_variable(name, s.as_str()),
+        };
+        // prebuilt rules may not declare it
+        if let Err(e) = defined {
+            debug!("external variable {} not set: {}", name, e);
+        }
+    }
+    Ok(scanner)
+}
+
+fn rule_info(rule: &Rule<'_>, source: Option<String>) -> RuleInfo {
+    let rule = RuleMatch::from(rule);
+    RuleInfo {
+        namespace: rule.namespace,
+        identifier: rule.identifier,
+        tags: rule.tags,
+        metadata: rule.metadata,
+        source,
+    }
+}
+
+/// The source of each rule by namespace and name. YARA doesn't keep track of it, so the rule
+/// declarations are looked up in the sources.
+fn declared_rules(rules_dir: &str, sources: &[PathBuf]) -> Result<HashMap<(String, String), String>> {
+    let mut declared = HashMap::new();
+    for source in sources {
+        let namespace = namespace(rules_dir, source);
+        for line in fs::read_to_string(source)?.lines() {
+            let mut tokens = line
+                .split_whitespace()
+                .skip_while(|t| *t == "private" || *t == "global");
+            if tokens.next() != Some("rule") {
+                continue;
+            }
+            let name = tokens
+                .next()
+                .and_then(|name| name.split(|c: char| c == ':' || c == '{').next())
+                .unwrap_or_default();
+            if !name.is_empty() {
+                declared.insert((namespace.clone(), name.to_string()), source.display().to_string());
+            }
+        }
+    }
+    Ok(declared)
+}
+
+fn rule_files<F: Fn(&str) -> bool>(rules_dir: &str, extension: F) -> Vec<PathBuf> {
+    let mut files = WalkDir::new(rules_dir)
+        .into_iter()
+        .filter_map(|f| f.ok())
+        .filter(|f| f.file_type().is_file())
+        .filter(|f| extension(&f.path().extension().unwrap_or_default().to_string_lossy()))
+        .map(|f| f.into_path())
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// Namespace of the rules in `source`.
+pub fn namespace(rules_dir: &str, source: &Path) -> String {
+    let relative = source.strip_prefix(rules_dir).unwrap_or(source);
+    match relative.parent() {
+        Some(dir) if dir != Path::new("") => dir
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        _ => relative.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+    }
+}
+
+/// The compiled sources, `None` when all of them were skipped, the skipped ones and when they
+/// were compiled. `key` is the `fingerprint` of the sources.
+fn compile_cached(
+    config: &Config,
+    sources: &[PathBuf],
+    key: &str,
+    externals: &Externals,
+) -> Result<(Option<Rules>, Vec<SkippedFile>, u64)> {
+    let skip_broken = *config.get_skip_broken_rules();
+    let name = format!("rules-{}", key);
+    let cache = Path::new(config.get_working_dir()).join(format!("{}.{}", name, COMPILED_EXTENSION));
+    let skipped_list = Path::new(config.get_working_dir()).join(format!("{}.json", name));
+    if skipped_list.exists() {
+        match load(&cache, &skipped_list) {
+            Ok(loaded) => {
+                info!("loaded cached rules {}", cache.display());
+                return Ok(loaded);
+            }
+            Err(e) => warn!("Failed to load the cached rules {}, recompiling: {}", cache.display(), e),
+        }
+    }
+
+    let rules_dir = config.get_rules_dir();
+    let mut accepted = Vec::new();
+    let mut skipped = Vec::new();
+    for source in sources {
+        if skip_broken {
+            // a failed compiler can't be reused, so each source gets its own
+            if let Err(error) = add_sources(new_compiler(externals)?, rules_dir, &[source]) {
+                skipped.push(skipped_file(source, error));
+                continue;
+            }
+        }
+        accepted.push(source);
+    }
+
+    let mut rules = None;
+    if !accepted.is_empty() {
+        let compiled = add_sources(new_compiler(externals)?, rules_dir, &accepted)
+            .and_then(|compiler| Ok(compiler.compile_rules()?));
+        rules = match compiled {
+            Ok(rules) => Some(rules),
+            Err(e) if skip_broken => {
+                // sources compiling alone may still clash, e.g. by declaring a rule of the same
+                // namespace again
+                warn!("The rules fail to compile together, checking them incrementally: {}", e);
+                let (kept, clashing) = without_clashes(externals, rules_dir, &accepted)?;
+                skipped.extend(clashing);
+                // the first one compiled alone, so some are kept
+                Some(add_sources(new_compiler(externals)?, rules_dir, &kept)?.compile_rules()?)
+            }
+            Err(e) => return Err(e),
+        };
+    }
+    if let Err(e) = save(rules.as_mut(), &skipped, &cache, &skipped_list) {
+        warn!("Failed to cache the compiled rules in {}: {}", cache.display(), e);
+    }
+    Ok((rules, skipped, now()))
+}
+
+/// The `sources` that compile with the ones before them, and the others. Each source is checked
+/// with all the accepted ones, so this is only done when they fail together.
+fn without_clashes<'a>(
+    externals: &Externals,
+    rules_dir: &str,
+    sources: &[&'a PathBuf],
+) -> Result<(Vec<&'a PathBuf>, Vec<SkippedFile>)> {
+    let mut kept = Vec::new();
+    let mut skipped = Vec::new();
+    for source in sources {
+        let checked = add_sources(new_compiler(externals)?, rules_dir, &kept)
+            .and_then(|compiler| add_sources(compiler, rules_dir, &[source]));
+        match checked {
+            Ok(_) => kept.push(*source),
+            Err(error) => skipped.push(skipped_file(source, error)),
+        }
+    }
+    Ok((kept, skipped))
+}
+
+fn skipped_file(source: &Path, error: Error) -> SkippedFile {
+    let error = match error {
+        Error::CompileError(ref errors) => errors.to_string(),
+        other => other.to_string(),
+    };
+    error!("Skipping {}: {}", source.display(), error);
+    SkippedFile {
+        path: source.display().to_string(),
+        error,
+    }
+}
+
+/// Add `sources` to `compiler`, each in its namespace.
+fn add_sources(compiler: Compiler, rules_dir: &str, sources: &[&PathBuf]) -> Result<Compiler> {
+    sources.iter().try_fold(compiler, |compiler, source| {
+        Ok(compiler.add_rules_file_with_namespace(source, &namespace(rules_dir, source))?)
+    })
+}
+
+fn load(cache: &Path, skipped_list: &Path) -> Result<(Option<Rules>, Vec<SkippedFile>, u64)> {
+    let skipped = serde_json::from_slice(&fs::read(skipped_list)?)?;
+    // written right after compiling
+    let compiled_at = fs::metadata(skipped_list)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    // absent when every source was skipped
+    let rules = if cache.exists() {
+        Some(Rules::load_from_file(&cache.display().to_string())?)
+    } else {
+        None
+    };
+    Ok((rules, skipped, compiled_at))
+}
+
+/// Hash of the YARA version, whether broken sources are skipped, the external variables and the
+/// path and contents of each source and of the files it includes.
+fn fingerprint(sources: &[PathBuf], skip_broken: bool, externals: &Externals) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(yara_version());
+    hasher.update([skip_broken as u8]);
+    hasher.update(serde_json::to_vec(externals)?);
+    for source in sources {
+        for file in std::iter::once(source.clone()).chain(included_files(source)) {
+            hasher.update(file.display().to_string());
+            hasher.update([0]);
+            // a missing include fails the compile, but must not hit the cache once it is there
+            hasher.update(fs::read(&file).unwrap_or_default());
+            hasher.update([0]);
+        }
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// The files `source` includes, directly or through its includes, in the order they are found.
+/// Relative paths are resolved against the including file's directory, like YARA does.
+fn included_files(source: &Path) -> Vec<PathBuf> {
+    let mut included = Vec::new();
+    let mut pending = vec![source.to_path_buf()];
+    while let Some(file) = pending.pop() {
+        let content = match fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        let dir = file.parent().unwrap_or_else(|| Path::new("."));
+        for line in content.lines() {
+            let path = line
+                .trim()
+                .strip_prefix("include")
+                .map(str::trim_start)
+                .and_then(|rest| rest.strip_prefix('"'))
+                .and_then(|rest| rest.split('"').next());
+            if let Some(path) = path {
+                let path = dir.join(path);
+                if path != source && !included.contains(&path) {
+                    included.push(path.clone());
+                    pending.push(path);
+                }
+            }
+        }
+    }
+    included
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Write `rules` to `cache` and the skipped sources to `skipped_list`, and remove the caches of
+/// older sources. The skipped list is written last and marks a complete cache.
+fn save(rules: Option<&mut Rules>, skipped: &[SkippedFile], cache: &Path, skipped_list: &Path) -> Result<()> {
+    let dir = cache.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+    for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let extension = entry.path().extension().unwrap_or_default().to_string_lossy().to_string();
+        if name.starts_with("rules-") && (extension == COMPILED_EXTENSION || extension == "json") {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    // written aside first, so a worker never loads a partial file
+    if let Some(rules) = rules {
+        let partial = cache.with_extension("partial");
+        rules.save(&partial.display().to_string())?;
+        fs::rename(&partial, cache)?;
+    }
+    let partial = skipped_list.with_extension("partial");
+    fs::write(&partial, serde_json::to_vec(skipped)?)?;
+    fs::rename(&partial, skipped_list)?;
+    info!("cached the compiled rules in {}", cache.display());
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! Optional sandbox applied once the daemon is set up: a Landlock ruleset limiting the file
+//! system to what yarad needs and a seccomp syscall allowlist. Both apply to the calling thread
+//! and the ones it starts later, so this runs before the runtime starts its threads.
+
+use crate::config::{Config, PolicyAction};
+use crate::error::*;
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, ABI,
+};
+use log::info;
+use seccompiler::{apply_filter, BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+/// Landlock ABI whose access rights are handled. Older kernels get the subset they support.
+const LANDLOCK_ABI: ABI = ABI::V2;
+
+/// Directories an `exec` action or a scan worker needs to run.
+const EXEC_DIRS: [&str; 5] = ["/bin", "/usr", "/lib", "/lib64", "/etc"];
+
+/// Apply the sandbox enabled in `config`. `config_path` stays readable for reloads.
+pub fn apply(config: &Config, config_path: &str) -> Result<()> {
+    let sandbox = config.get_sandbox();
+    if *sandbox.get_landlock() {
+        landlock(config, config_path)?;
+    }
+    if *sandbox.get_seccomp() {
+        seccomp(config)?;
+    }
+    Ok(())
+}
+
+/// Whether child processes are started, by `exec` or the scan workers.
+fn spawns(config: &Config) -> bool {
+    config.uses(PolicyAction::Exec) || *config.get_workers().get_count() > 0
+}
+
+fn landlock(config: &Config, config_path: &str) -> Result<()> {
+    let read = AccessFs::from_read(LANDLOCK_ABI);
+    let all = AccessFs::from_all(LANDLOCK_ABI);
+
+    // matched files are removed from the scan roots by quarantine and delete
+    let mut scan_access = read;
+    if *config.get_quarantine().get_on_match() || config.uses(PolicyAction::Quarantine) || config.uses(PolicyAction::Delete) {
+        scan_access |= AccessFs::RemoveFile;
+    }
+    let mut read_only = vec![config.get_rules_dir().to_string(), config_path.to_string(), "/etc".to_string(), "/dev/urandom".to_string()];
+    if spawns(config) {
+        read_only.extend(EXEC_DIRS.iter().map(|d| d.to_string()));
+        // the workers run the yarad binary
+        read_only.extend(std::env::current_exe().ok().map(|p| p.display().to_string()));
+    }
+    let mut writable = vec![
+        config.get_working_dir().to_string(),
+        config.get_quarantine().get_dir().to_string(),
+        config.get_webhook().get_spool_dir().to_string(),
+    ];
+    writable.extend(parent(config.get_pid_file()));
+    if let Some(file) = config.get_event_log().get_file() {
+        writable.extend(parent(file));
+    }
+
+    let status = Ruleset::default()
+        .handle_access(all)
+        .and_then(|r| r.create())
+        .and_then(|r| r.add_rules(path_beneath_rules(existing(config.get_sandbox().get_scan_roots()), scan_access)))
+        .and_then(|r| r.add_rules(path_beneath_rules(existing(&read_only), read)))
+        .and_then(|r| r.add_rules(path_beneath_rules(existing(&writable), all)))
+        .and_then(|r| r.restrict_self())
+        .map_err(|e| Error::Sandbox(format!("landlock: {}", e)))?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!("landlock ruleset enforced"),
+        RulesetStatus::PartiallyEnforced => info!("landlock ruleset partially enforced by this kernel"),
+        RulesetStatus::NotEnforced => {
+            return Err(Error::Sandbox("landlock is not supported or not enabled by this kernel".to_string()))
+        }
+    }
+    Ok(())
+}
+
+fn existing(paths: &[String]) -> Vec<PathBuf> {
+    paths.iter().map(PathBuf::from).filter(|p| p.exists()).collect()
+}
+
+fn parent(path: &str) -> Option<String> {
+    Path::new(path).parent().map(|p| p.display().to_string())
+}
+
+/// Syscalls made by the runtime, yara, the file actions and the webhook client. The scan
+/// workers inherit the filter. The runtime is built after the filter is applied, and its signal
+/// driver needs `socketpair`; glibc resolves the webhook hosts with `sendmmsg`.
+fn allowed_syscalls(config: &Config) -> Vec<i64> {
+    let mut syscalls = vec![
+        libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_pread64, libc::SYS_pwrite64,
+        libc::SYS_openat, libc::SYS_close, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx, libc::SYS_lseek,
+        libc::SYS_getdents64, libc::SYS_readlinkat, libc::SYS_faccessat, libc::SYS_faccessat2, libc::SYS_fcntl,
+        libc::SYS_ioctl, libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_ftruncate, libc::SYS_statfs, libc::SYS_fstatfs,
+        libc::SYS_unlinkat, libc::SYS_renameat2, libc::SYS_mkdirat, libc::SYS_fchmod,
+        libc::SYS_fchmodat, libc::SYS_fchown, libc::SYS_fchownat, libc::SYS_getcwd,
+        libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect, libc::SYS_mremap, libc::SYS_madvise, libc::SYS_brk,
+        libc::SYS_futex, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_epoll_create1, libc::SYS_eventfd2,
+        libc::SYS_ppoll, libc::SYS_pipe2, libc::SYS_dup, libc::SYS_dup3,
+        libc::SYS_socket, libc::SYS_connect, libc::SYS_bind, libc::SYS_listen, libc::SYS_accept4,
+        libc::SYS_socketpair, libc::SYS_recvfrom, libc::SYS_sendto, libc::SYS_recvmsg, libc::SYS_sendmsg,
+        libc::SYS_sendmmsg, libc::SYS_shutdown,
+        libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_getsockopt, libc::SYS_setsockopt,
+        libc::SYS_clone, libc::SYS_clone3, libc::SYS_set_robust_list, libc::SYS_rseq, libc::SYS_set_tid_address,
+        libc::SYS_exit, libc::SYS_exit_group, libc::SYS_gettid, libc::SYS_getpid, libc::SYS_tgkill,
+        libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+        libc::SYS_sched_yield, libc::SYS_sched_getaffinity, libc::SYS_prctl, libc::SYS_prlimit64,
+        libc::SYS_getrandom, libc::SYS_clock_gettime, libc::SYS_clock_nanosleep, libc::SYS_nanosleep,
+        libc::SYS_getuid, libc::SYS_geteuid, libc::SYS_getgid, libc::SYS_getegid, libc::SYS_uname,
+        libc::SYS_capget,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    syscalls.extend_from_slice(&[
+        libc::SYS_open, libc::SYS_stat, libc::SYS_lstat, libc::SYS_access, libc::SYS_readlink, libc::SYS_poll,
+        libc::SYS_epoll_wait, libc::SYS_pipe, libc::SYS_dup2, libc::SYS_unlink, libc::SYS_rename, libc::SYS_renameat,
+        libc::SYS_mkdir, libc::SYS_chmod, libc::SYS_chown, libc::SYS_lchown,
+        libc::SYS_arch_prctl,
+    ]);
+    if spawns(config) {
+        syscalls.extend_from_slice(&[libc::SYS_execve, libc::SYS_wait4, libc::SYS_kill]);
+        #[cfg(target_arch = "x86_64")]
+        syscalls.push(libc::SYS_vfork);
+    }
+    syscalls
+}
+
+/// Other syscalls fail with `EPERM`.
+fn seccomp(config: &Config) -> Result<()> {
+    let arch: TargetArch = std::env::consts::ARCH
+        .try_into()
+        .map_err(|e| Error::Sandbox(format!("seccomp: {:?}", e)))?;
+    let rules = allowed_syscalls(config)
+        .into_iter()
+        .map(|syscall| (syscall, Vec::new()))
+        .collect::<BTreeMap<_, _>>();
+    let program: BpfProgram = SeccompFilter::new(rules, SeccompAction::Errno(libc::EPERM as u32), SeccompAction::Allow, arch)
+        .map_err(|e| Error::Sandbox(format!("seccomp: {}", e)))?
+        .try_into()
+        .map_err(|e| Error::Sandbox(format!("seccomp: {}", e)))?;
+    apply_filter(&program)
+        .map_err(|e| Error::Sandbox(format!("seccomp filter not applied, is it supported by this kernel? {}", e)))?;
+    info!("seccomp filter applied");
+    Ok(())
+}
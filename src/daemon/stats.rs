@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Daemon wide counters, reported by the `STATS` command.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    requests: AtomicU64,
+    files_scanned: AtomicU64,
+    matches: AtomicU64,
+    errors: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            requests: AtomicU64::default(),
+            files_scanned: AtomicU64::default(),
+            matches: AtomicU64::default(),
+            errors: AtomicU64::default(),
+            rejected: AtomicU64::default(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn scanned(&self, files: u64, matches: u64) {
+        self.files_scanned.fetch_add(files, Ordering::Relaxed);
+        self.matches.fetch_add(matches, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `rules` is the number of the loaded rules.
+    pub fn report(&self, rules: usize) -> String {
+        format!(
+            "RULES: {}\nUPTIME: {}\nREQUESTS: {}\nFILES SCANNED: {}\nMATCHES: {}\nERRORS: {}\nREJECTED: {}\n",
+            rules,
+            self.started.elapsed().as_secs(),
+            self.requests.load(Ordering::Relaxed),
+            self.files_scanned.load(Ordering::Relaxed),
+            self.matches.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+        )
+    }
+}
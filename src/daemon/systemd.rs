@@ -0,0 +1,90 @@
+//! The parts of the systemd service protocol yarad uses, without libsystemd: readiness and
+//! watchdog notifications over `NOTIFY_SOCKET` and socket activation over `LISTEN_FDS`.
+
+use crate::error::*;
+use log::{info, warn};
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+
+/// First file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Send `state` (e.g. `READY=1`) to the service manager. Does nothing when not run by systemd.
+pub fn notify(state: &str) -> Result<()> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Like [`notify`], logging failures instead of returning them.
+pub fn notify_or_warn(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!("Failed to notify systemd of {}: {}", state.replace('\n', " "), e);
+    }
+}
+
+/// Spawn the task sending `WATCHDOG=1` at half the interval given in `WATCHDOG_USEC`.
+pub fn start_watchdog() -> Option<JoinHandle<()>> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let period = Duration::from_micros(usec) / 2;
+    info!("sending watchdog notifications every {:?}", period);
+    Some(tokio::spawn(async move {
+        let mut tick = interval(period);
+        loop {
+            tick.tick().await;
+            notify_or_warn("WATCHDOG=1");
+        }
+    }))
+}
+
+/// The socket passed by socket activation, if any. The environment is cleared so that it is
+/// adopted only once.
+pub fn take_listen_fd() -> Result<Option<RawFd>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(None),
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let fds = fds
+        .parse::<RawFd>()
+        .map_err(|_| Error::ConfigParseError { reason: format!("invalid LISTEN_FDS: {}", fds) })?;
+    // as sd_listen_fds does, so that the workers and exec commands don't inherit them
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    match fds {
+        0 => Ok(None),
+        1 => Ok(Some(LISTEN_FDS_START)),
+        n => {
+            warn!("{} sockets passed by systemd, only the first one is used", n);
+            Ok(Some(LISTEN_FDS_START))
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use crate::config::WebhookConfig;
+use crate::error::*;
+use crate::scan::ScanResult;
+use super::privilege::create_dir_for_user;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::{interval, sleep, MissedTickBehavior};
+
+/// How often the spool is retried when reports are sent one by one.
+const SPOOL_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Reports waiting for the worker; more are dropped.
+const QUEUE_SIZE: usize = 10_000;
+
+/// The JSON body posted for a matched file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatchReport {
+    pub timestamp: u64,
+    pub peer: String,
+    pub result: Value,
+}
+
+/// A delivery that ran out of retries, kept in the spool directory until the endpoint is back.
+#[derive(Debug, Serialize, Deserialize)]
+struct Spooled {
+    url: String,
+    payload: Value,
+}
+
+/// Posts match reports to the configured endpoints from a background task.
+#[derive(Debug)]
+pub struct Webhook {
+    sender: Sender<MatchReport>,
+    /// taken by `start`
+    worker: Mutex<Option<Worker>>,
+}
+
+#[derive(Debug)]
+struct Worker {
+    config: WebhookConfig,
+    client: reqwest::Client,
+    receiver: Receiver<MatchReport>,
+    /// endpoints that failed, spooled for without posting until `flush_spool` gets through
+    down: BTreeSet<String>,
+}
+
+impl Webhook {
+    /// `None` when no endpoint is configured.
+    pub fn new(config: WebhookConfig, user: &str) -> Result<Option<Self>> {
+        if config.get_urls().is_empty() {
+            return Ok(None);
+        }
+        create_dir_for_user(config.get_spool_dir(), user)?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(*config.get_timeout()))
+            .build()?;
+        let (sender, receiver) = channel(QUEUE_SIZE);
+        Ok(Some(Webhook {
+            sender,
+            worker: Mutex::new(Some(Worker {
+                config,
+                client,
+                receiver,
+                down: BTreeSet::new(),
+            })),
+        }))
+    }
+
+    /// Spawn the delivery task. Called from the running daemon, after daemonizing.
+    pub fn start(&self) {
+        if let Some(worker) = self.worker.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            tokio::spawn(worker.run());
+        }
+    }
+
+    /// Queue the matched `results` for delivery.
+    pub fn notify(&self, peer: &str, results: &[ScanResult]) {
+        let timestamp = now();
+        for result in results.iter().filter(|r| r.is_infected()) {
+            let report = serde_json::to_value(result).map(|result| MatchReport {
+                timestamp,
+                peer: peer.to_string(),
+                result,
+            });
+            match report {
+                Ok(report) => match self.sender.try_send(report) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        error!("webhook queue is full, dropped the report of {}", result.path)
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        error!("webhook worker is not running, dropped the report of {}", result.path)
+                    }
+                },
+                Err(e) => error!("Failed to serialize the report of {}: {}", result.path, e),
+            }
+        }
+    }
+}
+
+impl Worker {
+    async fn run(mut self) {
+        let batch_interval = *self.config.get_batch_interval();
+        let mut tick = interval(if batch_interval > 0 {
+            Duration::from_secs(batch_interval)
+        } else {
+            SPOOL_RETRY_INTERVAL
+        });
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut batch = Vec::new();
+        info!("webhook started for {}", self.config.get_urls().join(", "));
+        loop {
+            tokio::select! {
+                report = self.receiver.recv() => match report {
+                    Some(report) if batch_interval > 0 => batch.push(report),
+                    Some(report) => match serde_json::to_value(&report) {
+                        Ok(payload) => self.deliver(&payload).await,
+                        Err(e) => error!("Failed to serialize the webhook payload: {}", e),
+                    },
+                    None => break,
+                },
+                _ = tick.tick() => {
+                    self.flush_spool().await;
+                    if !batch.is_empty() {
+                        match serde_json::to_value(&batch) {
+                            Ok(payload) => self.deliver(&payload).await,
+                            Err(e) => error!("Failed to serialize the webhook payload: {}", e),
+                        }
+                        batch.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Post `payload` to every endpoint, spooling it for the endpoints still failing after the
+    /// retries and, without posting, for the ones down since.
+    async fn deliver(&mut self, payload: &Value) {
+        for url in self.config.get_urls() {
+            if !self.down.contains(url) {
+                match self.post_with_retry(url, payload).await {
+                    Ok(()) => continue,
+                    Err(e) => error!("Failed to deliver to {}, spooling until it is back: {}", url, e),
+                }
+                self.down.insert(url.clone());
+            }
+            if let Err(e) = self.spool(url, payload) {
+                error!("Failed to spool the webhook payload: {}", e);
+            }
+        }
+    }
+
+    /// Retry with exponential backoff, starting at `backoff` seconds.
+    async fn post_with_retry(&self, url: &str, payload: &Value) -> Result<()> {
+        let mut delay = Duration::from_secs(*self.config.get_backoff());
+        let mut attempt = 0;
+        loop {
+            match self.post(url, payload).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < *self.config.get_retries() => {
+                    warn!("Failed to post to {} (attempt {}): {}", url, attempt + 1, e);
+                    sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn post(&self, url: &str, payload: &Value) -> Result<()> {
+        self.client
+            .post(url)
+            .json(payload)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn spool(&self, url: &str, payload: &Value) -> Result<()> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let path = Path::new(self.config.get_spool_dir()).join(format!("{}.json", nanos));
+        let spooled = Spooled {
+            url: url.to_string(),
+            payload: payload.clone(),
+        };
+        fs::write(path, serde_json::to_vec(&spooled)?)?;
+        Ok(())
+    }
+
+    /// Resend the spooled payloads in order. The payloads of an endpoint still down stay in the
+    /// spool, the others are sent anyway and their endpoints are posted to again.
+    async fn flush_spool(&mut self) {
+        let mut files = match fs::read_dir(self.config.get_spool_dir()) {
+            Ok(dir) => dir
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().unwrap_or_default() == "json")
+                .collect::<Vec<PathBuf>>(),
+            Err(e) => {
+                error!("Failed to read the webhook spool: {}", e);
+                return;
+            }
+        };
+        files.sort();
+        let mut down = BTreeSet::new();
+        for file in files {
+            let spooled: Spooled = match fs::read(&file).map_err(Error::from).and_then(|data| Ok(serde_json::from_slice(&data)?)) {
+                Ok(spooled) => spooled,
+                Err(e) => {
+                    warn!("Dropping unreadable spool file {}: {}", file.display(), e);
+                    let _ = fs::remove_file(&file);
+                    continue;
+                }
+            };
+            // keeps the order of the payloads of each endpoint
+            if down.contains(&spooled.url) {
+                continue;
+            }
+            if let Err(e) = self.post(&spooled.url, &spooled.payload).await {
+                warn!("{} is still unreachable: {}", spooled.url, e);
+                down.insert(spooled.url);
+                continue;
+            }
+            if let Err(e) = fs::remove_file(&file) {
+                error!("Failed to remove the delivered spool file {}: {}", file.display(), e);
+                continue;
+            }
+            info!("delivered spooled report to {}", spooled.url);
+        }
+        self.down = down;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use serde_json::json;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Local HTTP endpoint answering every request with `status`. Returns its url and the
+    /// bodies it received.
+    async fn stand_in(status: u16) -> (String, Arc<Mutex<Vec<Value>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/yarad", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let bodies = received.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                let body = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break None;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let headers = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                        let len = headers
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .and_then(|len| len.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + len {
+                            break Some(request[end + 4..end + 4 + len].to_vec());
+                        }
+                    }
+                };
+                if let Some(body) = body {
+                    bodies.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                }
+                let response = format!("HTTP/1.1 {} Stand-in\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, received)
+    }
+
+    /// Worker posting to `urls` with one retry, spooling in a fresh directory named after `name`.
+    fn worker(name: &str, urls: &[&str]) -> Worker {
+        let dir = std::env::temp_dir().join(format!("yarad-webhook-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let urls = urls.iter().map(|url| format!("\"{}\"", url)).collect::<Vec<_>>().join(", ");
+        let config = format!(
+            "working_dir: {}\nwebhook:\n  urls: [{}]\n  retries: 1\n  backoff: 0\n  timeout: 5\n  spool_dir: {}\n",
+            dir.display(),
+            urls,
+            dir.join("spool").display()
+        );
+        let path = dir.join("config.yml");
+        fs::write(&path, config).unwrap();
+        let config = Config::try_from(path.display().to_string()).unwrap();
+        let webhook = Webhook::new(config.get_webhook().clone(), "root").unwrap().unwrap();
+        let worker = webhook.worker.lock().unwrap().take();
+        worker.unwrap()
+    }
+
+    fn spooled(worker: &Worker) -> Vec<Spooled> {
+        let mut files = fs::read_dir(worker.config.get_spool_dir())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+            .iter()
+            .map(|file| serde_json::from_slice(&fs::read(file).unwrap()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn delivers_to_the_endpoint() {
+        let (url, received) = stand_in(200).await;
+        let mut worker = worker("deliver", &[&url]);
+        let payload = json!({"timestamp": 1, "peer": "uid 1000", "result": {"path": "/tmp/sample"}});
+        worker.deliver(&payload).await;
+        assert_eq!(*received.lock().unwrap(), vec![payload]);
+        assert!(spooled(&worker).is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_then_spools() {
+        let (url, received) = stand_in(500).await;
+        let mut worker = worker("spool", &[&url]);
+        let payload = json!({"timestamp": 2, "peer": "uid 1000", "result": {"path": "/tmp/sample"}});
+        worker.deliver(&payload).await;
+        // the first attempt and the retry
+        assert_eq!(received.lock().unwrap().len(), 2);
+        let spooled = spooled(&worker);
+        assert_eq!(spooled.len(), 1);
+        assert_eq!(spooled[0].url, url);
+        assert_eq!(spooled[0].payload, payload);
+    }
+
+    #[tokio::test]
+    async fn spools_without_posting_while_down() {
+        let (url, received) = stand_in(500).await;
+        let mut worker = worker("down", &[&url]);
+        worker.deliver(&json!({"n": 1})).await;
+        worker.deliver(&json!({"n": 2})).await;
+        // only the first payload was posted, with its retry
+        assert_eq!(*received.lock().unwrap(), vec![json!({"n": 1}), json!({"n": 1})]);
+        let spooled = spooled(&worker);
+        assert_eq!(spooled.iter().map(|s| s.payload.clone()).collect::<Vec<_>>(), vec![json!({"n": 1}), json!({"n": 2})]);
+    }
+
+    #[tokio::test]
+    async fn flushes_the_spool_past_a_down_endpoint() {
+        let (up, received) = stand_in(200).await;
+        let (down, refused) = stand_in(503).await;
+        let mut worker = worker("flush", &[&up, &down]);
+        worker.spool(&down, &json!({"n": 1})).unwrap();
+        worker.spool(&up, &json!({"n": 2})).unwrap();
+        worker.spool(&down, &json!({"n": 3})).unwrap();
+        worker.flush_spool().await;
+        assert_eq!(*received.lock().unwrap(), vec![json!({"n": 2})]);
+        // the endpoint is skipped after the first failure
+        assert_eq!(*refused.lock().unwrap(), vec![json!({"n": 1})]);
+        let spooled = spooled(&worker);
+        assert_eq!(spooled.iter().map(|s| s.payload.clone()).collect::<Vec<_>>(), vec![json!({"n": 1}), json!({"n": 3})]);
+        assert!(spooled.iter().all(|s| s.url == down));
+        assert!(worker.down.contains(&down));
+        assert!(!worker.down.contains(&up));
+    }
+}
@@ -0,0 +1,258 @@
+//! Scan worker processes, so that a crash or runaway allocation while YARA parses hostile input
+//! takes down a worker instead of the daemon. Workers are `yarad worker` processes reading one
+//! JSON request per line on stdin and answering with one JSON line on stdout, after a first
+//! line telling that the rules are loaded.
+
+use crate::config::{Config, Externals, WorkersConfig};
+use crate::error::*;
+use crate::scan::{FileId, RuleMatch};
+use super::rule::RuleSet;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Read, Write};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::timeout;
+
+/// Subcommand of the yarad binary running a worker.
+pub const WORKER_COMMAND: &str = "worker";
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    File(String, Externals),
+    /// followed by this many bytes of data
+    Bytes(usize, Externals),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    /// sent once the rules are loaded
+    Ready,
+    /// and the scanned file, unless data was scanned
+    Matches(Vec<RuleMatch>, Option<FileId>),
+    Error(String),
+}
+
+/// Serve requests on stdin until it is closed. Runs in the worker process.
+pub fn serve(config: &Config) -> Result<()> {
+    let rules = RuleSet::load(config)?;
+    let timeout = *config.get_scan_timeout();
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    serde_json::to_writer(&mut output, &Response::Ready)?;
+    output.write_all(b"\n")?;
+    output.flush()?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let scanned = match serde_json::from_str(&line)? {
+            Request::File(path, externals) => rules
+                .scan_file(&path, timeout, &externals)
+                .map(|(matches, file)| (matches, Some(file))),
+            Request::Bytes(len, externals) => {
+                let mut data = vec![0; len];
+                input.read_exact(&mut data)?;
+                rules.scan_mem(&data, timeout, &externals).map(|matches| (matches, None))
+            }
+        };
+        let response = match scanned {
+            Ok((matches, file)) => Response::Matches(matches, file),
+            Err(e) => Response::Error(e.to_string()),
+        };
+        serde_json::to_writer(&mut output, &response)?;
+        output.write_all(b"\n")?;
+        output.flush()?;
+    }
+}
+
+#[derive(Debug)]
+struct Process {
+    /// of the pool when spawned
+    generation: u64,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Process {
+    async fn exchange(&mut self, request: &Request, data: Option<&[u8]>) -> Result<Response> {
+        let mut message = serde_json::to_vec(request)?;
+        message.push(b'\n');
+        if let Some(data) = data {
+            message.extend_from_slice(data);
+        }
+        self.stdin.write_all(&message).await?;
+        self.stdin.flush().await?;
+        self.response().await
+    }
+
+    async fn response(&mut self) -> Result<Response> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Kill the worker and describe how it ended.
+    async fn kill(mut self) -> String {
+        if let Err(e) = self.child.start_kill() {
+            warn!("failed to kill worker: {}", e);
+        }
+        match self.child.wait().await {
+            Ok(status) => status.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+}
+
+/// Fixed size pool of workers, spawned on demand and replaced when they fail.
+#[derive(Debug)]
+pub struct WorkerPool {
+    config: WorkersConfig,
+    config_path: String,
+    idle: Mutex<Vec<Process>>,
+    slots: Semaphore,
+    /// bumped by `restart`, workers of an older one are not reused
+    generation: AtomicU64,
+}
+
+impl WorkerPool {
+    /// `None` when workers are disabled.
+    pub fn new(config: WorkersConfig, config_path: String) -> Option<Self> {
+        if *config.get_count() == 0 {
+            return None;
+        }
+        Some(WorkerPool {
+            slots: Semaphore::new(*config.get_count()),
+            config,
+            config_path,
+            idle: Mutex::new(Vec::new()),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    pub async fn scan_file(&self, path: &str, externals: Externals) -> Result<(Vec<RuleMatch>, FileId)> {
+        match self.request(Request::File(path.to_string(), externals), None).await? {
+            (matches, Some(file)) => Ok((matches, file)),
+            (_, None) => Err(Error::WorkerFailed("no scanned file in the response".to_string())),
+        }
+    }
+
+    pub async fn scan_bytes(&self, data: &[u8], externals: Externals) -> Result<Vec<RuleMatch>> {
+        Ok(self.request(Request::Bytes(data.len(), externals), Some(data)).await?.0)
+    }
+
+    /// Replace the workers, e.g. after the rules changed. Busy workers finish their scan first.
+    pub async fn restart(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        // dropped children are killed
+        self.idle.lock().await.clear();
+        info!("scan workers restarted");
+    }
+
+    async fn request(&self, request: Request, data: Option<&[u8]>) -> Result<(Vec<RuleMatch>, Option<FileId>)> {
+        let _slot = self.slots.acquire().await.map_err(|e| Error::WorkerFailed(e.to_string()))?;
+        let idle = self.idle.lock().await.pop();
+        let mut process = match idle {
+            Some(process) => process,
+            None => self.spawn().await?,
+        };
+
+        let limit = Duration::from_secs(*self.config.get_timeout());
+        match timeout(limit, process.exchange(&request, data)).await {
+            Ok(Ok(response)) => {
+                // checked under the lock, so that `restart` clears it if it runs meanwhile
+                let mut idle = self.idle.lock().await;
+                if process.generation == self.generation.load(Ordering::SeqCst) {
+                    idle.push(process);
+                } else {
+                    drop(idle);
+                    // restarted while busy, it still has the old rules
+                    process.kill().await;
+                }
+                match response {
+                    Response::Matches(matches, file) => Ok((matches, file)),
+                    Response::Error(e) => Err(Error::ScanFailed(e)),
+                    Response::Ready => Err(Error::WorkerFailed("unexpected ready".to_string())),
+                }
+            }
+            Ok(Err(e)) => {
+                let status = process.kill().await;
+                error!("scan worker failed ({}): {}", status, e);
+                Err(Error::WorkerFailed(format!("worker died: {}", status)))
+            }
+            Err(_) => {
+                process.kill().await;
+                error!("scan worker timed out after {:?}", limit);
+                Err(Error::WorkerFailed(format!("worker timed out after {}s", limit.as_secs())))
+            }
+        }
+    }
+
+    /// Start a worker and wait for it to load the rules, bounded by the load timeout rather than
+    /// the scan timeout.
+    async fn spawn(&self) -> Result<Process> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let mut command = Command::new(std::env::current_exe()?);
+        command
+            .arg("--config")
+            .arg(&self.config_path)
+            .arg(WORKER_COMMAND)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(limit) = *self.config.get_memory_limit() {
+            let bytes = limit * 1024 * 1024;
+            unsafe {
+                command.pre_exec(move || {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: bytes,
+                        rlim_max: bytes,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_AS, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().ok_or_else(|| Error::WorkerFailed("no stdin".to_string()))?;
+        let stdout = child.stdout.take().ok_or_else(|| Error::WorkerFailed("no stdout".to_string()))?;
+        info!("spawned scan worker {}", child.id().unwrap_or_default());
+        let mut process = Process {
+            generation,
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+        };
+        let limit = Duration::from_secs(*self.config.get_load_timeout());
+        match timeout(limit, process.response()).await {
+            Ok(Ok(Response::Ready)) => Ok(process),
+            Ok(Ok(_)) => {
+                process.kill().await;
+                Err(Error::WorkerFailed("worker did not get ready".to_string()))
+            }
+            Ok(Err(e)) => {
+                let status = process.kill().await;
+                error!("scan worker failed to load the rules ({}): {}", status, e);
+                Err(Error::WorkerFailed(format!("worker died while loading the rules: {}", status)))
+            }
+            Err(_) => {
+                process.kill().await;
+                error!("scan worker did not load the rules within {:?}", limit);
+                Err(Error::WorkerFailed(format!("worker took longer than {}s to load the rules", limit.as_secs())))
+            }
+        }
+    }
+}
@@ -13,6 +13,10 @@ pub enum Error {
     Daemonize(#[from] DaemonizeError),
     #[error("Daemon does not running. please start daemon first.")]
     DaemonNotRunning,
+    #[error("Daemon is already running as pid {0}")]
+    AlreadyRunning(i32),
+    #[error("Invalid pid file: {0}")]
+    InvalidPidFile(String),
     #[error("Permission denied in {0}")]
     NoPermission(String),
     #[error("Config file not found: {0}")]
@@ -39,6 +43,8 @@ pub enum Error {
     },
     #[error("Log level parse error")]
     ParseLogLevelError,
+    #[error("Logger initialize error: `{0}`")]
+    SetLogger(#[from] log::SetLoggerError),
     #[error(transparent)]
     AnyHow(#[from] anyhow::Error),
     #[error("Polling failed")]
@@ -55,6 +61,33 @@ pub enum Error {
     FromUtf8Error(#[from] std::string::FromUtf8Error),
     #[error("Invalid Path: `{0}`")]
     InvalidPath(String),
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(&'static str),
+    #[error("Scan failed: `{0}`")]
+    ScanFailed(String),
+    #[error("Reload failed: `{0}`")]
+    ReloadFailed(String),
+    #[error("Request rejected by daemon: `{0}`")]
+    Rejected(String),
+    #[error("Unexpected reply from daemon: `{0}`")]
+    UnexpectedReply(String),
+    #[error("{action} failed: `{reason}`")]
+    ActionFailed {
+        action: String,
+        reason: String,
+    },
+    #[error("JSON error: `{0}`")]
+    Json(#[from] serde_json::Error),
+    #[error("Not authorized: {0}")]
+    NotAuthorized(String),
+    #[error("Rule not found: `{0}`")]
+    RuleNotFound(String),
+    #[error("Scan worker failed: {0}")]
+    WorkerFailed(String),
+    #[error("Sandbox error: {0}")]
+    Sandbox(String),
+    #[error("HTTP error: `{0}`")]
+    Http(#[from] reqwest::Error),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -1 +1,302 @@
+//! Detection events, written as JSON lines and optionally to syslog or journald, and the setup
+//! of the debug logging done through the `log` crate.
 
+use crate::config::EventLogConfig;
+use crate::error::*;
+use crate::scan::{RuleMatch, ScanResult};
+use log::{error, info, LevelFilter};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SYSLOG_PATH: &str = "/dev/log";
+const JOURNALD_PATH: &str = "/run/systemd/journal/socket";
+/// facility daemon (3)
+const SYSLOG_FACILITY: u8 = 3;
+
+const LOG_ENV: &str = "RUST_LOG";
+
+/// Initialize the debug logger at `level`. `RUST_LOG`, when set, takes precedence.
+pub fn init_logger(level: &str) -> Result<()> {
+    let mut builder = env_logger::Builder::new();
+    match env::var(LOG_ENV) {
+        Ok(filters) => {
+            builder.parse_filters(&filters);
+        }
+        // filtered by the max level instead, so that it can be changed at runtime
+        Err(_) => {
+            builder.filter_level(LevelFilter::Trace);
+        }
+    }
+    builder.try_init()?;
+    if env::var_os(LOG_ENV).is_none() {
+        log::set_max_level(parse_level(level)?);
+    }
+    Ok(())
+}
+
+/// Apply the configured `level`, unless overridden by `RUST_LOG`.
+pub fn apply_log_level(level: &str) -> Result<()> {
+    if env::var_os(LOG_ENV).is_some() {
+        info!("{} is set, ignoring log_level {}", LOG_ENV, level);
+        return Ok(());
+    }
+    set_log_level(level).map(|_| ())
+}
+
+/// Change the level until the next reload. Can't go beyond the filters in `RUST_LOG`.
+pub fn set_log_level(level: &str) -> Result<LevelFilter> {
+    let level = parse_level(level)?;
+    log::set_max_level(level);
+    info!("log level set to {}", level);
+    Ok(level)
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    Ok(LevelFilter::from_str(level)?)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    /// a scan request finished
+    Scan,
+    /// a file or stream matched
+    Match,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    pub timestamp: u64,
+    pub event: EventKind,
+    pub peer: String,
+    /// scanned path, or `stream` for `INSTREAM`
+    pub path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u128>,
+    #[serde(skip_serializing_if = "is_empty")]
+    pub rules: &'a [RuleMatch],
+    #[serde(skip_serializing_if = "is_empty")]
+    pub actions: &'a [String],
+}
+
+impl Event<'_> {
+    fn message(&self) -> String {
+        match self.event {
+            EventKind::Scan => format!("scanned {} ({} files)", self.path, self.files.unwrap_or_default()),
+            EventKind::Match => format!(
+                "{} matched {}",
+                self.path,
+                self.rules.iter().map(|r| r.name()).collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+
+    /// warning for matches, informational otherwise
+    fn severity(&self) -> u8 {
+        match self.event {
+            EventKind::Scan => 6,
+            EventKind::Match => 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+#[derive(Debug)]
+pub struct EventLog {
+    config: EventLogConfig,
+    file: Option<Mutex<LogFile>>,
+    syslog: Option<UnixDatagram>,
+    journald: Option<UnixDatagram>,
+}
+
+impl EventLog {
+    pub fn new(config: EventLogConfig) -> Result<Self> {
+        let file = match config.get_file() {
+            Some(path) => Some(Mutex::new(LogFile::open(PathBuf::from(path))?)),
+            None => None,
+        };
+        let syslog = if *config.get_syslog() { Some(connect(SYSLOG_PATH)?) } else { None };
+        let journald = if *config.get_journald() { Some(connect(JOURNALD_PATH)?) } else { None };
+        Ok(EventLog {
+            config,
+            file,
+            syslog,
+            journald,
+        })
+    }
+
+    /// Record a finished scan of `target` and every match in `results`.
+    pub fn scan(&self, peer: &str, target: &str, results: &[ScanResult], duration: Duration) {
+        let timestamp = now();
+        for result in results.iter().filter(|r| r.is_infected()) {
+            self.record(&Event {
+                timestamp,
+                event: EventKind::Match,
+                peer: peer.to_string(),
+                path: &result.path,
+                files: None,
+                duration_ms: None,
+                rules: &result.details,
+                actions: &result.actions,
+            });
+        }
+        self.record(&Event {
+            timestamp,
+            event: EventKind::Scan,
+            peer: peer.to_string(),
+            path: target,
+            files: Some(results.len()),
+            duration_ms: Some(duration.as_millis()),
+            rules: &[],
+            actions: &[],
+        });
+    }
+
+    /// Write `event` to every configured sink. Failures are logged and otherwise ignored so that
+    /// they never fail a scan.
+    pub fn record(&self, event: &Event) {
+        if let Some(file) = &self.file {
+            let written = serde_json::to_string(event).map_err(Error::from).and_then(|line| {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                file.write_line(&line, *self.config.get_max_size(), *self.config.get_keep())
+            });
+            if let Err(e) = written {
+                error!("Failed to write the event log: {}", e);
+            }
+        }
+        if let Some(socket) = &self.syslog {
+            if let Err(e) = socket.send(syslog_message(event).as_bytes()) {
+                error!("Failed to send the event to syslog: {}", e);
+            }
+        }
+        if let Some(socket) = &self.journald {
+            if let Err(e) = socket.send(&journald_message(event)) {
+                error!("Failed to send the event to journald: {}", e);
+            }
+        }
+    }
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // opened by root at start, in a directory belonging to the daemon's user
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { path, file, size })
+    }
+
+    fn write_line(&mut self, line: &str, max_size: u64, keep: usize) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > max_size {
+            self.rotate(keep)?;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1`, dropping those beyond `keep`, and start a new file.
+    fn rotate(&mut self, keep: usize) -> Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..keep).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        *self = LogFile::open(self.path.clone())?;
+        Ok(())
+    }
+}
+
+fn is_empty<T>(items: &&[T]) -> bool {
+    items.is_empty()
+}
+
+fn connect(path: &str) -> Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// RFC 5424 message with the event kind as MSGID and no structured data, as yarad has no
+/// enterprise number of its own. Timestamp and hostname are left to the syslog daemon.
+fn syslog_message(event: &Event) -> String {
+    let mut message = format!("{}, peer {}", event.message(), event.peer);
+    if !event.actions.is_empty() {
+        message.push_str(&format!(", actions: {}", event.actions.join(", ")));
+    }
+    format!(
+        "<{}>1 - - yarad {} {} - {}",
+        SYSLOG_FACILITY * 8 + event.severity(),
+        std::process::id(),
+        kind(event),
+        message
+    )
+}
+
+/// Native journald protocol, with every field in the length prefixed form so that values may
+/// contain newlines.
+fn journald_message(event: &Event) -> Vec<u8> {
+    let mut fields = vec![
+        ("MESSAGE", event.message()),
+        ("PRIORITY", event.severity().to_string()),
+        ("SYSLOG_IDENTIFIER", "yarad".to_string()),
+        ("YARAD_EVENT", kind(event).to_string()),
+        ("YARAD_PEER", event.peer.clone()),
+        ("YARAD_PATH", event.path.to_string()),
+    ];
+    fields.extend(event.rules.iter().map(|r| ("YARAD_RULE", r.name())));
+    fields.extend(event.actions.iter().map(|a| ("YARAD_ACTION", a.clone())));
+
+    let mut message = Vec::new();
+    for (key, value) in fields {
+        message.extend_from_slice(key.as_bytes());
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        message.extend_from_slice(value.as_bytes());
+        message.push(b'\n');
+    }
+    message
+}
+
+fn kind(event: &Event) -> &'static str {
+    match event.event {
+        EventKind::Scan => "scan",
+        EventKind::Match => "match",
+    }
+}
@@ -1,12 +1,98 @@
-use std::convert::{TryFrom, From};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::string::ToString;
 use crate::error::*;
-use crate::client::args;
+use crate::daemon::quarantine::QuarantineEntry;
+use crate::daemon::rule::{RuleInfo, RulesetInfo, SkippedFile};
+use crate::scan::ScanResult;
 use log::info;
+use serde::de::DeserializeOwned;
+
+/// Size of the chunks sent after `INSTREAM`. Each chunk is prefixed with its length as a 4 byte
+/// big endian integer, and a zero length chunk terminates the stream.
+pub const INSTREAM_CHUNK_SIZE: usize = 8192;
+
+/// Path reported in the scan results of `INSTREAM`.
+pub const INSTREAM_PATH: &str = "stream";
+
+/// Reply line terminating each command's reply inside a session.
+pub const SESSION_DONE: &str = "DONE";
+
+/// Prefix of the reply lines reporting an action taken on a matched file.
+pub const ACTION_PREFIX: &str = "ACTION ";
+
+/// Prefix of the reply lines reporting a file that could not be scanned, followed by the reason.
+pub const ERROR_PREFIX: &str = "ERROR ";
+
+/// Prefix of the `STATUS` reply lines reporting a rule file left out, followed by the reason.
+pub const SKIPPED_PREFIX: &str = "SKIPPED ";
+
+/// Prefix of the reply line following `RELOADED` with the changed settings needing a restart.
+pub const RESTART_REQUIRED_PREFIX: &str = "RESTART REQUIRED: ";
+
+/// Option of the scan commands selecting the namespaces whose matches are reported.
+const NAMESPACE_OPTION: &str = "NAMESPACE=";
+
+/// Option of the scan commands setting an external variable, `VAR:<name>=<value>`.
+const VAR_OPTION: &str = "VAR:";
+
+/// Options given to a scan command before its path, e.g.
+/// `SCAN NAMESPACE=malware,pe VAR:environment=staging /path`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// report only the matches of rules in these namespaces, every namespace when empty
+    pub namespaces: Vec<String>,
+    /// external variables overriding the defaults and the ones set for each file. The values
+    /// can't contain spaces
+    pub externals: BTreeMap<String, String>,
+}
+
+impl ScanOptions {
+    /// Whether matches of a rule in `namespace` are reported.
+    pub fn selects(&self, namespace: &str) -> bool {
+        self.namespaces.is_empty() || self.namespaces.iter().any(|n| n == namespace)
+    }
+
+    /// Split the leading options off `args`.
+    fn parse(mut args: &str) -> (Self, &str) {
+        let mut options = ScanOptions::default();
+        loop {
+            if let Some(rest) = args.strip_prefix(NAMESPACE_OPTION) {
+                let (value, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                options
+                    .namespaces
+                    .extend(value.split(',').filter(|n| !n.is_empty()).map(String::from));
+                args = rest.trim_start();
+            } else if let Some((name, value, rest)) = args.strip_prefix(VAR_OPTION).and_then(|rest| {
+                let (var, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                var.split_once('=').map(|(name, value)| (name, value, rest))
+            }) {
+                options.externals.insert(name.to_string(), value.to_string());
+                args = rest.trim_start();
+            } else {
+                return (options, args);
+            }
+        }
+    }
+}
+
+impl ToString for ScanOptions {
+    /// The options followed by a space, empty without any.
+    fn to_string(&self) -> String {
+        let mut options = String::new();
+        if !self.namespaces.is_empty() {
+            options.push_str(&format!("{}{} ", NAMESPACE_OPTION, self.namespaces.join(",")));
+        }
+        for (name, value) in &self.externals {
+            options.push_str(&format!("{}{}={} ", VAR_OPTION, name, value));
+        }
+        options
+    }
+}
 
 #[derive(Debug)]
 pub enum Command {
-    /// Check the daemon's state. It should reply with "PONG\0".
+    /// Check the daemon's state. It should reply with "PONG\n".
     Ping,
     /// Check the daemon's version.
     Version,
@@ -14,15 +100,64 @@ pub enum Command {
     Reload,
     /// Shutdown the daemon.
     Shutdown,
-    /// Scan the file or directory at the given path (recursively).
-    Scan(String),
+    /// Scan the file or directory at the given path (recursively). Like the other scan commands,
+    /// the path may be preceded by `NAMESPACE=<namespace>[,<namespace>...]` and
+    /// `VAR:<name>=<value>` options.
+    Scan(String, ScanOptions),
     /// Scan the file or directory at the given path (recursively) and don't stop the scanning
     /// when a malware found.
-    ContScan(String),
+    ContScan(String, ScanOptions),
     /// Scan the file or directory at the given path (recursively) using multi thread.
-    MultiScan(String),
-    /// Scan the file inside stream.
-    InstreamScan(String),
+    MultiScan(String, ScanOptions),
+    /// Scan the data sent in chunks after the command.
+    Instream(ScanOptions),
+    /// Show the daemon's statistics.
+    Stats,
+    /// Show the number of loaded rules and the rule files skipped because they failed to compile.
+    Status,
+    /// Change the log level until the next reload.
+    LogLevel(String),
+    /// Start a session. Following commands are answered on the same connection, each reply line
+    /// prefixed with the command's number and terminated by a "DONE" line.
+    IdSession,
+    /// End the session.
+    End,
+    /// List the quarantined files, one JSON object per line.
+    QuarantineList,
+    /// Put the quarantined file with the given id back to its original path.
+    QuarantineRestore(String),
+    /// Delete the quarantined file with the given id.
+    QuarantineDelete(String),
+    /// List the loaded rules, optionally of one namespace, one JSON object per line.
+    RulesList(Option<String>),
+    /// Show the rules named `<rule>` or `<namespace>:<rule>`, one JSON object per line.
+    RulesShow(String),
+    /// Show the fingerprint and compile time of the loaded rules as a JSON object.
+    RulesInfo,
+    /// Stop reporting the matches of the rule `<namespace>:<rule>`, until it is enabled again.
+    RuleDisable(String),
+    /// Report the matches of the disabled rule `<namespace>:<rule>` again.
+    RuleEnable(String),
+    /// List the disabled rules, one per line.
+    RuleListDisabled,
+}
+
+impl Command {
+    /// Commands changing the daemon, the quarantine or the reported rules, or listing the
+    /// quarantined files of all users, only run for the admins.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Command::Reload
+                | Command::Shutdown
+                | Command::LogLevel(_)
+                | Command::QuarantineList
+                | Command::QuarantineRestore(_)
+                | Command::QuarantineDelete(_)
+                | Command::RuleDisable(_)
+                | Command::RuleEnable(_)
+        )
+    }
 }
 
 impl ToString for Command {
@@ -32,25 +167,28 @@ impl ToString for Command {
             Command::Version => "zVERSION\0".into(),
             Command::Reload => "zRELOAD\0".into(),
             Command::Shutdown => "zSHUTDOWN\0".into(),
-            Command::Scan(s) => format!("zSCAN {}\0", s),
-            Command::ContScan(s) => format!("zCONTSCAN {}\0", s),
-            Command::MultiScan(s) => format!("zMULTISCAN {}\0", s),
-            Command::InstreamScan(s) => format!("zINSTREAM {}\0", s)
-        }
-    }
-}
-
-impl From<&args::Command> for Vec<Command> {
-    fn from(cmd: &args::Command) -> Self {
-        match cmd {
-            args::Command::Ping => vec![Command::Ping],
-            args::Command::Version => vec![Command::Version],
-            args::Command::Reload => vec![Command::Reload],
-            args::Command::Shutdown => vec![Command::Shutdown],
-            args::Command::Scan{path} => path.iter().map(|p| Command::Scan(p.to_string())).collect(),
-            args::Command::ContScan{path} => path.iter().map(|p| Command::ContScan(p.to_string())).collect(),
-            args::Command::MultiScan{path} => path.iter().map(|p| Command::MultiScan(p.to_string())).collect(),
-            args::Command::InstreamScan{path} => path.iter().map(|p| Command::InstreamScan(p.to_string())).collect(),
+            Command::Scan(s, options) => format!("zSCAN {}{}\0", options.to_string(), s),
+            Command::ContScan(s, options) => format!("zCONTSCAN {}{}\0", options.to_string(), s),
+            Command::MultiScan(s, options) => format!("zMULTISCAN {}{}\0", options.to_string(), s),
+            Command::Instream(options) => match options.to_string().trim_end() {
+                "" => "zINSTREAM\0".into(),
+                options => format!("zINSTREAM {}\0", options),
+            },
+            Command::Stats => "zSTATS\0".into(),
+            Command::Status => "zSTATUS\0".into(),
+            Command::LogLevel(level) => format!("zLOGLEVEL {}\0", level),
+            Command::IdSession => "zIDSESSION\0".into(),
+            Command::End => "zEND\0".into(),
+            Command::QuarantineList => "zQUARANTINE LIST\0".into(),
+            Command::QuarantineRestore(id) => format!("zQUARANTINE RESTORE {}\0", id),
+            Command::QuarantineDelete(id) => format!("zQUARANTINE DELETE {}\0", id),
+            Command::RulesList(None) => "zRULES LIST\0".into(),
+            Command::RulesList(Some(namespace)) => format!("zRULES LIST {}\0", namespace),
+            Command::RulesShow(name) => format!("zRULES SHOW {}\0", name),
+            Command::RulesInfo => "zRULES INFO\0".into(),
+            Command::RuleDisable(name) => format!("zRULE DISABLE {}\0", name),
+            Command::RuleEnable(name) => format!("zRULE ENABLE {}\0", name),
+            Command::RuleListDisabled => "zRULE LIST-DISABLED\0".into(),
         }
     }
 }
@@ -66,34 +204,86 @@ impl TryFrom<&str> for Command {
             "VERSION" => Ok(Command::Version),
             "RELOAD" => Ok(Command::Reload),
             "SHUTDOWN" => Ok(Command::Shutdown),
+            "STATS" => Ok(Command::Stats),
+            "STATUS" => Ok(Command::Status),
+            "INSTREAM" => Ok(Command::Instream(ScanOptions::default())),
+            "IDSESSION" => Ok(Command::IdSession),
+            "END" => Ok(Command::End),
+            "QUARANTINE LIST" => Ok(Command::QuarantineList),
+            "RULES LIST" => Ok(Command::RulesList(None)),
+            "RULES INFO" => Ok(Command::RulesInfo),
+            "RULE LIST-DISABLED" => Ok(Command::RuleListDisabled),
             other => {
                 if let Some(path) = other.strip_prefix("SCAN ") {
-                    let path = path.trim();
+                    let (options, path) = ScanOptions::parse(path.trim());
                     if path.is_empty() {
                         Err(Error::InvalidCommand(s.to_string()))
                     } else {
-                        Ok(Command::Scan(path.to_string()))
+                        Ok(Command::Scan(path.to_string(), options))
                     }
                 } else if let Some(path) = other.strip_prefix("CONTSCAN ") {
-                    let path = path.trim();
+                    let (options, path) = ScanOptions::parse(path.trim());
                     if path.is_empty() {
                         Err(Error::InvalidCommand(s.to_string()))
                     } else {
-                        Ok(Command::ContScan(path.to_string()))
+                        Ok(Command::ContScan(path.to_string(), options))
                     }
                 } else if let Some(path) = other.strip_prefix("MULTISCAN ") {
-                    let path = path.trim();
+                    let (options, path) = ScanOptions::parse(path.trim());
                     if path.is_empty() {
                         Err(Error::InvalidCommand(s.to_string()))
                     } else {
-                        Ok(Command::MultiScan(path.to_string()))
+                        Ok(Command::MultiScan(path.to_string(), options))
                     }
-                } else if let Some(path) = other.strip_prefix("INSTREAM ") {
-                    let path = path.trim();
-                    if path.is_empty() {
+                } else if let Some(args) = other.strip_prefix("INSTREAM ") {
+                    match ScanOptions::parse(args.trim()) {
+                        (options, "") => Ok(Command::Instream(options)),
+                        _ => Err(Error::InvalidCommand(s.to_string())),
+                    }
+                } else if let Some(level) = other.strip_prefix("LOGLEVEL ") {
+                    let level = level.trim();
+                    if level.is_empty() {
+                        Err(Error::InvalidCommand(s.to_string()))
+                    } else {
+                        Ok(Command::LogLevel(level.to_string()))
+                    }
+                } else if let Some(id) = other.strip_prefix("QUARANTINE RESTORE ") {
+                    let id = id.trim();
+                    if id.is_empty() {
                         Err(Error::InvalidCommand(s.to_string()))
                     } else {
-                        Ok(Command::InstreamScan(path.to_string()))
+                        Ok(Command::QuarantineRestore(id.to_string()))
+                    }
+                } else if let Some(id) = other.strip_prefix("QUARANTINE DELETE ") {
+                    let id = id.trim();
+                    if id.is_empty() {
+                        Err(Error::InvalidCommand(s.to_string()))
+                    } else {
+                        Ok(Command::QuarantineDelete(id.to_string()))
+                    }
+                } else if let Some(namespace) = other.strip_prefix("RULES LIST ") {
+                    let namespace = namespace.trim();
+                    if namespace.is_empty() {
+                        Ok(Command::RulesList(None))
+                    } else {
+                        Ok(Command::RulesList(Some(namespace.to_string())))
+                    }
+                } else if let Some(name) = other.strip_prefix("RULE DISABLE ") {
+                    match qualified_rule(name) {
+                        Some(name) => Ok(Command::RuleDisable(name)),
+                        None => Err(Error::InvalidCommand(s.to_string())),
+                    }
+                } else if let Some(name) = other.strip_prefix("RULE ENABLE ") {
+                    match qualified_rule(name) {
+                        Some(name) => Ok(Command::RuleEnable(name)),
+                        None => Err(Error::InvalidCommand(s.to_string())),
+                    }
+                } else if let Some(name) = other.strip_prefix("RULES SHOW ") {
+                    let name = name.trim();
+                    if name.is_empty() {
+                        Err(Error::InvalidCommand(s.to_string()))
+                    } else {
+                        Ok(Command::RulesShow(name.to_string()))
                     }
                 } else {
                     Err(Error::InvalidCommand(s.to_string()))
@@ -103,26 +293,209 @@ impl TryFrom<&str> for Command {
     }
 }
 
-pub fn parse_commands(data: Vec<u8>) -> Result<Vec<Result<Command>>> {
-    let data = String::from_utf8(data)?;
-
-    if data.is_empty() {
-        return Ok(Vec::new())
+/// `<namespace>:<rule>`, trimmed, `None` when not qualified with a namespace.
+fn qualified_rule(name: &str) -> Option<String> {
+    let name = name.trim();
+    match name.split_once(':') {
+        Some((namespace, rule)) if !namespace.is_empty() && !rule.is_empty() => Some(name.to_string()),
+        _ => None,
     }
+}
 
-    let delim_type = data.chars().next().unwrap();
-    info!("Delimiter type: {}", delim_type);
+/// Split the first complete command off the front of `buf`.
+///
+/// Returns `Ok(None)` when `buf` does not hold a complete command yet.
+pub fn take_command(buf: &mut Vec<u8>) -> Result<Option<Result<Command>>> {
+    let delim_type = match buf.first() {
+        Some(c) => *c,
+        None => return Ok(None),
+    };
+    info!("Delimiter type: {}", delim_type as char);
     let delimiter = match delim_type {
-        'z' => Ok('\0'),
-        'n' => Ok('\n'),
-        _ => Err(Error::InvalidCommand(format!("Invalid delimiter specification: {}", delim_type))),
+        b'z' => Ok(b'\0'),
+        b'n' => Ok(b'\n'),
+        _ => Err(Error::InvalidCommand(format!("Invalid delimiter specification: {}", delim_type as char))),
     }?;
 
-    let commands = data
-        .split(delimiter)
-        .filter(|s| s.starts_with(delim_type))
-        .map(|s| Command::try_from(&s[1..]))
-        .collect::<Vec<_>>();
+    let end = match buf.iter().position(|c| *c == delimiter) {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let raw = buf.drain(..=end).collect::<Vec<_>>();
+    let command = String::from_utf8(raw[1..end].to_vec())?;
+
+    Ok(Some(Command::try_from(&command[..])))
+}
+
+pub fn parse_commands(mut data: Vec<u8>) -> Result<Vec<Result<Command>>> {
+    let mut commands = Vec::new();
+    while let Some(command) = take_command(&mut data)? {
+        commands.push(command);
+    }
 
     Ok(commands)
 }
+
+/// Encode `data` as a single `INSTREAM` chunk.
+pub fn instream_chunk(data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(data);
+    chunk
+}
+
+/// Turn the daemon's error replies into errors, returning the reply unchanged otherwise.
+pub fn check_reply(reply: &str) -> Result<&str> {
+    for line in reply.lines() {
+        if let Some(reason) = line.strip_prefix("Error while scanning: ") {
+            return Err(Error::ScanFailed(reason.to_string()));
+        }
+        if let Some(reason) = line.strip_prefix("Error while disabling: ") {
+            return Err(Error::ActionFailed {
+                action: "disable".to_string(),
+                reason: reason.to_string(),
+            });
+        }
+        if let Some(reason) = line.strip_prefix("Error while enabling: ") {
+            return Err(Error::ActionFailed {
+                action: "enable".to_string(),
+                reason: reason.to_string(),
+            });
+        }
+        if let Some(reason) = line.strip_prefix("Error while reloading: ") {
+            return Err(Error::ReloadFailed(reason.to_string()));
+        }
+        if let Some(reason) = line.strip_prefix("Invalid command: ") {
+            return Err(Error::InvalidCommand(reason.to_string()));
+        }
+        if let Some(reason) = line.strip_prefix("Limit exceeded: ") {
+            return Err(Error::Rejected(reason.to_string()));
+        }
+        if let Some(reason) = line.strip_prefix("Not authorized: ") {
+            return Err(Error::NotAuthorized(reason.to_string()));
+        }
+        if let Some(name) = line.strip_prefix("Rule not found: ") {
+            return Err(Error::RuleNotFound(name.to_string()));
+        }
+        if let Some(reason) = line.strip_prefix("Quarantine error: ") {
+            return Err(Error::ActionFailed {
+                action: "quarantine".to_string(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+    Ok(reply)
+}
+
+/// Parse the reply to `SCAN` or `INSTREAM`. Each line is either `<namespace>:<rule>: <path>`,
+/// `OK: <path>`, `ACTION <action>: <path>` for what the daemon did with a matched file or
+/// `ERROR <reason>: <path>` for a file that could not be scanned.
+pub fn parse_scan_reply(reply: &str) -> Result<Vec<ScanResult>> {
+    let mut results: Vec<ScanResult> = Vec::new();
+    for line in check_reply(reply)?.lines() {
+        let (rule, path) = line
+            .split_once(": ")
+            .ok_or_else(|| Error::UnexpectedReply(line.to_string()))?;
+        if let Some(action) = rule.strip_prefix(ACTION_PREFIX) {
+            match results.last_mut() {
+                Some(last) if last.path == path => last.actions.push(action.to_string()),
+                _ => return Err(Error::UnexpectedReply(line.to_string())),
+            }
+            continue;
+        }
+        if let Some(reason) = rule.strip_prefix(ERROR_PREFIX) {
+            results.push(ScanResult::failed(path.to_string(), reason.to_string()));
+            continue;
+        }
+        match results.last_mut() {
+            Some(last) if last.path == path => last.rule.push(rule.to_string()),
+            _ => results.push(ScanResult {
+                rule: vec![rule.to_string()],
+                path: path.to_string(),
+                actions: Vec::new(),
+                details: Vec::new(),
+                error: None,
+                file: None,
+            }),
+        }
+    }
+    Ok(results)
+}
+
+/// Parse the reply to `RELOAD` into the changed settings that need a restart.
+pub fn parse_reload_reply(reply: &str) -> Result<Vec<String>> {
+    let mut lines = check_reply(reply)?.lines();
+    if lines.next() != Some("RELOADED") {
+        return Err(Error::UnexpectedReply(reply.to_string()));
+    }
+    Ok(lines
+        .filter_map(|line| line.strip_prefix(RESTART_REQUIRED_PREFIX))
+        .flat_map(|settings| settings.split(", "))
+        .map(String::from)
+        .collect())
+}
+
+/// Format the `ERROR` reply line; `: ` would end the reason early.
+pub fn error_line(reason: &str, path: &str) -> String {
+    reason_line(ERROR_PREFIX, reason, path)
+}
+
+/// Format the `SKIPPED` reply line of `STATUS`.
+pub fn skipped_line(file: &SkippedFile) -> String {
+    reason_line(SKIPPED_PREFIX, &file.error, &file.path)
+}
+
+fn reason_line(prefix: &str, reason: &str, path: &str) -> String {
+    format!("{}{}: {}\n", prefix, reason.trim_end().replace(": ", " - ").replace('\n', " "), path)
+}
+
+/// The daemon's rules as reported by `STATUS`.
+#[derive(Debug, Clone)]
+pub struct RulesStatus {
+    pub rules: usize,
+    pub skipped: Vec<SkippedFile>,
+}
+
+/// Parse the reply to `STATUS`: `RULES: <count>` followed by a `SKIPPED <reason>: <path>` line
+/// for each rule file left out.
+pub fn parse_status_reply(reply: &str) -> Result<RulesStatus> {
+    let mut lines = check_reply(reply)?.lines();
+    let rules = lines
+        .next()
+        .and_then(|line| line.strip_prefix("RULES: "))
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| Error::UnexpectedReply(reply.to_string()))?;
+    let skipped = lines
+        .map(|line| {
+            line.strip_prefix(SKIPPED_PREFIX)
+                .and_then(|line| line.split_once(": "))
+                .map(|(error, path)| SkippedFile {
+                    path: path.to_string(),
+                    error: error.to_string(),
+                })
+                .ok_or_else(|| Error::UnexpectedReply(line.to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RulesStatus { rules, skipped })
+}
+
+/// One line of the `QUARANTINE LIST` reply.
+pub fn parse_quarantine_list(reply: &str) -> Result<Vec<QuarantineEntry>> {
+    parse_json_lines(reply)
+}
+
+/// Parse the reply to `RULES LIST` or `RULES SHOW`.
+pub fn parse_rules_list(reply: &str) -> Result<Vec<RuleInfo>> {
+    parse_json_lines(reply)
+}
+
+/// Parse the reply to `RULES INFO`.
+pub fn parse_rules_info(reply: &str) -> Result<RulesetInfo> {
+    Ok(serde_json::from_str(check_reply(reply)?.trim_end())?)
+}
+
+fn parse_json_lines<T: DeserializeOwned>(reply: &str) -> Result<Vec<T>> {
+    check_reply(reply)?
+        .lines()
+        .map(|line| serde_json::from_str(line).map_err(Error::from))
+        .collect()
+}
@@ -1,16 +1,144 @@
-use yara::Rule;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use yara::{MetadataValue, Rule};
 
-#[derive(Debug)]
+/// Rule name reported for files without any match.
+pub const CLEAN: &str = "OK";
+
+/// A matched rule with the attributes policies select on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleMatch {
+    pub identifier: String,
+    pub namespace: String,
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl RuleMatch {
+    /// `namespace:identifier`, as reported in replies.
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.namespace, self.identifier)
+    }
+}
+
+impl From<&Rule<'_>> for RuleMatch {
+    fn from(rule: &Rule<'_>) -> Self {
+        RuleMatch {
+            identifier: rule.identifier.to_string(),
+            namespace: rule.namespace.to_string(),
+            tags: rule.tags.iter().map(|t| t.to_string()).collect(),
+            metadata: rule
+                .metadatas
+                .iter()
+                .map(|m| {
+                    let value = match &m.value {
+                        MetadataValue::Integer(i) => i.to_string(),
+                        MetadataValue::String(s) => s.to_string(),
+                        MetadataValue::Boolean(b) => b.to_string(),
+                    };
+                    (m.identifier.to_string(), value)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct ScanResult {
     pub rule: Vec<String>,
     pub path: String,
+    /// What the daemon did with the file after the match, e.g. `quarantine OK <id>`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<String>,
+    /// Details of the matched rules. Only known to the daemon, replies carry the names alone.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<RuleMatch>,
+    /// Why the file could not be scanned, e.g. its scan worker crashed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The scanned file, which the actions check they act on.
+    #[serde(skip)]
+    pub file: Option<FileId>,
+}
+
+/// Identity of a file, as the path may point to another one by the time it is acted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl From<&Metadata> for FileId {
+    fn from(metadata: &Metadata) -> Self {
+        FileId {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }
+    }
 }
 
 impl ScanResult {
     pub fn new(rule: Vec<Rule>, path: String) -> Self {
+        ScanResult::from_matches(rule.iter().map(RuleMatch::from).collect(), path)
+    }
+
+    pub fn from_matches(details: Vec<RuleMatch>, path: String) -> Self {
+        if details.is_empty() {
+            return ScanResult::clean(path);
+        }
+        ScanResult {
+            rule: details.iter().map(RuleMatch::name).collect(),
+            path,
+            actions: Vec::new(),
+            details,
+            error: None,
+            file: None,
+        }
+    }
+
+    pub fn clean(path: String) -> Self {
+        ScanResult {
+            rule: vec![CLEAN.to_string()],
+            path,
+            actions: Vec::new(),
+            details: Vec::new(),
+            error: None,
+            file: None,
+        }
+    }
+
+    /// A file that could not be scanned.
+    pub fn failed(path: String, error: String) -> Self {
         ScanResult {
-            rule: rule.into_iter().map(|x| x.identifier.to_string()).collect(),
-            path
+            rule: Vec::new(),
+            path,
+            actions: Vec::new(),
+            details: Vec::new(),
+            error: Some(error),
+            file: None,
         }
     }
+
+    /// Record which file was scanned.
+    pub fn scanned(mut self, file: FileId) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    /// Neither matched nor failed.
+    pub fn is_clean(&self) -> bool {
+        self.error.is_none() && self.rule.iter().all(|r| r == CLEAN)
+    }
+
+    /// At least one rule matched.
+    pub fn is_infected(&self) -> bool {
+        self.matches().next().is_some()
+    }
+
+    /// Matched rule names, without the `OK` marker.
+    pub fn matches(&self) -> impl Iterator<Item = &String> {
+        self.rule.iter().filter(|r| *r != CLEAN)
+    }
 }
@@ -4,13 +4,15 @@ use tokio::net::{
     TcpListener,
     TcpStream,
 };
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::path::Path;
 use std::os::unix::fs::PermissionsExt;
 use std::fs::Permissions;
 use crate::config::{Config, StreamType};
 use crate::error::*;
-use crate::protocol::*;
+use crate::daemon::limit::Peer;
 use log::info;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 const BUFFER_SIZE: usize = 4096;
 
@@ -20,8 +22,15 @@ pub enum Listener {
     Tcp(TcpListener),
 }
 
-impl Listener {
-    pub async fn new(config: &Config) -> Result<Listener> {
+/// A listening socket opened before the runtime starts, while still root.
+#[derive(Debug)]
+pub enum StdListener {
+    Unix(std::os::unix::net::UnixListener),
+    Tcp(std::net::TcpListener),
+}
+
+impl StdListener {
+    pub fn bind(config: &Config) -> Result<StdListener> {
         Ok(
             match *config.get_stream_type() {
                 StreamType::Unix => {
@@ -29,18 +38,54 @@ impl Listener {
                     if Path::new(socket_path).exists() {
                         std::fs::remove_file(socket_path)?;
                     }
-                    let listener = Listener::Unix(UnixListener::bind(socket_path)?);
-                    tokio::fs::set_permissions(socket_path, Permissions::from_mode(*config.get_local_socket_mode())).await?;
+                    let listener = StdListener::Unix(std::os::unix::net::UnixListener::bind(socket_path)?);
+                    std::fs::set_permissions(socket_path, Permissions::from_mode(*config.get_local_socket_mode()))?;
                     info!("Listening on {}, perm {:#o}", socket_path, *config.get_local_socket_mode());
                     listener
-
                 },
                 StreamType::Tcp => {
-                    Listener::Tcp(TcpListener::bind(("0.0.0.0", *config.get_tcp_port())).await?)
+                    StdListener::Tcp(std::net::TcpListener::bind(("0.0.0.0", *config.get_tcp_port()))?)
                 },
             }
         )
     }
+
+    /// Adopt a listening socket passed by socket activation.
+    pub fn from_fd(fd: RawFd) -> Result<StdListener> {
+        let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let listener = if addr.ss_family as libc::c_int == libc::AF_UNIX {
+            StdListener::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+        } else {
+            StdListener::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        };
+        info!("Listening on the socket passed by systemd");
+        Ok(listener)
+    }
+}
+
+impl Listener {
+    pub async fn new(config: &Config) -> Result<Listener> {
+        Listener::from_std(StdListener::bind(config)?)
+    }
+
+    /// Register a socket opened before the runtime started.
+    pub fn from_std(listener: StdListener) -> Result<Listener> {
+        Ok(match listener {
+            StdListener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                Listener::Unix(UnixListener::from_std(listener)?)
+            },
+            StdListener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(TcpListener::from_std(listener)?)
+            },
+        })
+    }
+
     pub async fn accept(&self) -> Result<Stream> {
         match self {
             Listener::Unix(listener) => {
@@ -62,6 +107,14 @@ pub enum Stream {
 }
 
 impl Stream {
+    /// Identify the remote end: UID for Unix sockets, IP address for TCP.
+    pub fn peer(&self) -> Peer {
+        match self {
+            Self::Unix(s) => s.peer_cred().map(|cred| Peer::Uid(cred.uid())).unwrap_or(Peer::Unknown),
+            Self::Tcp(s) => s.peer_addr().map(|addr| Peer::Ip(addr.ip())).unwrap_or(Peer::Unknown),
+        }
+    }
+
     pub async fn readable(&self) -> Result<()> {
         match self {
             Self::Unix(s) => s.readable().await.map_err(|e| e.into()),
@@ -76,48 +129,28 @@ impl Stream {
         }
     }
 
-    pub fn try_parse_commands(&self) -> Result<Vec<Result<Command>>> {
-        let raw = self.try_read_to_end()?;
-        parse_commands(raw)
+    /// Read whatever is available into `buf`. Returns 0 at end of stream.
+    pub async fn read_buf(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        if buf.capacity() - buf.len() < BUFFER_SIZE {
+            buf.reserve(BUFFER_SIZE);
+        }
+        match self {
+            Self::Unix(s) => s.read_buf(buf).await.map_err(|e| e.into()),
+            Self::Tcp(s) => s.read_buf(buf).await.map_err(|e| e.into()),
+        }
     }
 
-    pub fn try_read_to_end(&self) -> Result<Vec<u8>> {
+    pub async fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
         match self {
-            Self::Unix(s) => {
-                let mut buf = vec![0; BUFFER_SIZE];
-                let mut n = s.try_read(&mut buf[..])?;
-                let mut size = n;
-                while n == BUFFER_SIZE {
-                    let mut tmp = vec![0; BUFFER_SIZE];
-                    n = s.try_read(&mut tmp[..])?;
-                    buf.extend_from_slice(&tmp[..n]);
-                    tmp.clear();
-                    size += n;
-                }
-                buf.truncate(size);
-                Ok(buf)
-            },
-            Self::Tcp(s) => {
-                let mut buf = vec![0; BUFFER_SIZE];
-                let mut n = s.try_read(&mut buf[..])?;
-                let mut size = n;
-                while n == BUFFER_SIZE {
-                    let mut tmp = vec![0; BUFFER_SIZE];
-                    n = s.try_read(&mut tmp[..])?;
-                    buf.extend_from_slice(&tmp[..n]);
-                    tmp.clear();
-                    size += n;
-                }
-                buf.truncate(size);
-                Ok(buf)
-            },
+            Self::Unix(s) => s.read_to_end(buf).await.map_err(|e| e.into()),
+            Self::Tcp(s) => s.read_to_end(buf).await.map_err(|e| e.into()),
         }
     }
 
-    pub fn try_write(&self, buf: &[u8]) -> Result<usize> {
+    pub async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
         match self {
-            Self::Unix(s) => s.try_write(buf).map_err(|e| e.into()),
-            Self::Tcp(s) => s.try_write(buf).map_err(|e| e.into()),
+            Self::Unix(s) => s.write_all(buf).await.map_err(|e| e.into()),
+            Self::Tcp(s) => s.write_all(buf).await.map_err(|e| e.into()),
         }
     }
 }